@@ -1,59 +1,265 @@
+use crate::handles::HandleTable;
+use crate::permissions::{self, PermissionsMode};
 use crate::store::{
+    acl::{Acl, ACL_ACCESS, ACL_DEFAULT},
     etcd_store::EtcdStore,
     memory_store::MemoryStore,
-    store::{Store, StoreType},
+    store::{AttrChanges, Invalidation, Store, StoreError, StoreResult, StoreType, NAME_MAX},
 };
-use fuser::{consts::FOPEN_KEEP_CACHE, Filesystem};
-use libc::ENOENT;
+use fuser::{
+    consts::{
+        FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE, FUSE_ATOMIC_O_TRUNC, FUSE_DONT_MASK,
+        FUSE_DO_READDIRPLUS, FUSE_POSIX_ACL, FUSE_READDIRPLUS_AUTO,
+    },
+    FileAttr, FileType, Filesystem, KernelConfig, Notifier,
+};
+use libc::{EISDIR, ERANGE, O_EXCL, R_OK, W_OK, X_OK};
+use std::os::unix::ffi::OsStrExt;
+use std::sync::mpsc;
 use std::time::{Duration, SystemTime};
 
 const TTL: Duration = Duration::from_secs(1);
+const BLOCK_SIZE: u32 = 512;
+// Entries fetched from the store at a time while filling a readdirplus reply
+const DIR_BATCH: usize = 128;
+// Namespace of the extended attributes users can set on the files they can write
+const USER_XATTR_PREFIX: &str = "user.";
+// Namespace of the extended attributes only root can read and write
+const TRUSTED_XATTR_PREFIX: &str = "trusted.";
+// Namespace of the extended attributes of security modules, anyone can read but only root write
+const SECURITY_XATTR_PREFIX: &str = "security.";
 
 // Filesystem implementation that handles regular files and directories
 // Uses a store with a concise API to handle all filesystem operations
 pub struct FuseFS {
     store: Box<dyn Store<Ino = u64>>,
+    permissions: PermissionsMode,
+    handles: HandleTable,
 }
 
 impl FuseFS {
-    pub fn new(store_type: &StoreType) -> Self {
+    pub fn new(
+        store_type: &StoreType,
+        permissions: PermissionsMode,
+        invalidations: mpsc::Sender<Invalidation>,
+    ) -> Self {
         match store_type {
             StoreType::InMemory => {
-                let store = MemoryStore::new().unwrap();
+                let store = MemoryStore::new(invalidations).unwrap();
                 return Self {
                     store: Box::new(store),
+                    permissions,
+                    handles: HandleTable::default(),
                 };
             }
             StoreType::Etcd => {
-                let store = EtcdStore::new().unwrap();
+                let store = EtcdStore::new(invalidations).unwrap();
                 return Self {
                     store: Box::new(store),
+                    permissions,
+                    handles: HandleTable::default(),
                 };
             }
         }
     }
+
+    // Opens `ino` in the store and hands out a new handle for it, along with the open reply flags
+    fn open_handle(&mut self, ino: u64, flags: i32) -> StoreResult<(u64, u32)> {
+        self.store.open_file(ino, flags)?;
+        let fh = self.handles.open(ino, flags);
+
+        // O_DIRECT goes around the page cache, other opens keep what is cached
+        if self.handles.get(fh)?.direct() {
+            Ok((fh, FOPEN_DIRECT_IO))
+        } else {
+            Ok((fh, FOPEN_KEEP_CACHE))
+        }
+    }
+
+    // Lets the store persist what was written through the handle, or the whole file on fsync
+    fn flush_handle(&mut self, fh: u64, sync: bool) -> StoreResult<()> {
+        let handle = self.handles.get_mut(fh)?;
+
+        if handle.dirty || sync {
+            self.store.flush_file(handle.ino)?;
+            handle.dirty = false;
+        }
+
+        Ok(())
+    }
+
+    // Attributes and access ACL the permission checks are made against
+    fn get_file_access(&self, ino: u64) -> StoreResult<(FileAttr, Option<Acl>)> {
+        let (attr, acl) = self.store.get_file_access(ino)?;
+
+        Ok((attr, acl.map(|value| Acl::from_bytes(&value)).transpose()?))
+    }
+
+    // Checks done by the filesystem are skipped when the kernel already does them
+    fn check_access(&self, req: &fuser::Request<'_>, ino: u64, mask: i32) -> StoreResult<()> {
+        if self.permissions == PermissionsMode::Kernel {
+            return Ok(());
+        }
+
+        let (attr, acl) = self.get_file_access(ino)?;
+        permissions::check_access(&attr, acl.as_ref(), req.uid(), &request_groups(req), mask)
+    }
+
+    // User extended attributes are read and written with the permissions of the file content,
+    // the other namespaces have their own rules
+    fn check_xattr_access(
+        &self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &str,
+        mask: i32,
+    ) -> StoreResult<()> {
+        if self.permissions == PermissionsMode::Kernel {
+            return Ok(());
+        }
+
+        let root_only = name.starts_with(TRUSTED_XATTR_PREFIX)
+            || (name.starts_with(SECURITY_XATTR_PREFIX) && mask & W_OK != 0);
+
+        if name.starts_with(USER_XATTR_PREFIX) {
+            self.check_access(req, ino, mask)
+        } else if root_only {
+            if req.uid() == 0 {
+                Ok(())
+            } else {
+                Err(StoreError::NotPermitted)
+            }
+        } else if (name == ACL_ACCESS || name == ACL_DEFAULT) && mask == R_OK {
+            // The parents were searched on lookup, a directory must be searchable itself
+            if self.store.get_file_attr(ino)?.kind == FileType::Directory {
+                self.check_access(req, ino, X_OK)
+            } else {
+                Ok(())
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    // Only the owner can change the ACLs of a file
+    fn check_acl_owner(&self, req: &fuser::Request<'_>, ino: u64, name: &str) -> StoreResult<()> {
+        if self.permissions == PermissionsMode::Kernel
+            || (name != ACL_ACCESS && name != ACL_DEFAULT)
+        {
+            return Ok(());
+        }
+
+        let attr = self.store.get_file_attr(ino)?;
+        if req.uid() != 0 && req.uid() != attr.uid {
+            return Err(StoreError::NotPermitted);
+        }
+
+        Ok(())
+    }
+
+    // Removing an entry takes write and search access on its parent, and ownership if sticky
+    fn check_remove(
+        &self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+    ) -> StoreResult<()> {
+        if self.permissions == PermissionsMode::Kernel {
+            return Ok(());
+        }
+
+        let (dir, acl) = self.get_file_access(parent)?;
+        let groups = request_groups(req);
+        permissions::check_access(&dir, acl.as_ref(), req.uid(), &groups, W_OK | X_OK)?;
+        let (_, info) = self.store.lookup_file(name.to_owned(), parent)?;
+        permissions::check_sticky(&dir, &info.attr, req.uid())
+    }
+
+    // Renaming removes the entry from `parent` and any entry it replaces in `newparent`
+    fn check_rename(
+        &self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        newparent: u64,
+        newname: &std::ffi::OsStr,
+    ) -> StoreResult<()> {
+        if self.permissions == PermissionsMode::Kernel {
+            return Ok(());
+        }
+
+        self.check_remove(req, parent, name)?;
+        let (newdir, acl) = self.get_file_access(newparent)?;
+        let groups = request_groups(req);
+        permissions::check_access(&newdir, acl.as_ref(), req.uid(), &groups, W_OK | X_OK)?;
+
+        match self.store.lookup_file(newname.to_owned(), newparent) {
+            Ok((_, target)) => permissions::check_sticky(&newdir, &target.attr, req.uid())?,
+            Err(StoreError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+
+        // A directory moved to another parent has its ".." entry rewritten
+        if parent != newparent {
+            let (ino, _) = self.store.lookup_file(name.to_owned(), parent)?;
+            let (attr, acl) = self.get_file_access(ino)?;
+            if attr.kind == FileType::Directory {
+                permissions::check_access(&attr, acl.as_ref(), req.uid(), &groups, W_OK)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Filesystem for FuseFS {
+    fn init(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        config: &mut KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        // The umask is left to the stores, a default ACL on the parent replaces it
+        let _ = config.add_capabilities(FUSE_DONT_MASK);
+        // O_TRUNC is passed on to open, for the stores to truncate as they open the file
+        let _ = config.add_capabilities(FUSE_ATOMIC_O_TRUNC);
+        // Listings come with the attributes of each entry, saving a lookup per entry
+        let _ = config.add_capabilities(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO);
+
+        // Lets the kernel enforce ACLs along with the mode when it does the checks
+        if self.permissions == PermissionsMode::Kernel {
+            let _ = config.add_capabilities(FUSE_POSIX_ACL);
+        }
+
+        Ok(())
+    }
+
     // Files
     fn unlink(
         &mut self,
         _req: &fuser::Request<'_>,
-        _parent: u64,
+        parent: u64,
         name: &std::ffi::OsStr,
         reply: fuser::ReplyEmpty,
     ) {
         //dbg!("UNLINK");
-        let _res = self.store.delete_file(name.to_str().unwrap().to_owned());
+        if let Err(e) = self.check_remove(_req, parent, name) {
+            reply.error(e.errno());
+            return;
+        }
 
-        reply.ok();
+        let res = self.store.delete_file(parent, name.to_owned());
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
     }
 
     fn write(
         &mut self,
         _req: &fuser::Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _write_flags: u32,
@@ -62,22 +268,36 @@ impl Filesystem for FuseFS {
         reply: fuser::ReplyWrite,
     ) {
         //dbg!("WRITE");
-        let written = self.store.write_data(ino, data, offset).unwrap();
+        let written = match self.handles.get_mut(fh) {
+            Ok(handle) if handle.writable() => {
+                handle.dirty = true;
+                self.store.write_data(ino, data, offset, handle.append())
+            }
+            Ok(_) => Err(StoreError::BadFileHandle),
+            Err(e) => Err(e),
+        };
 
-        reply.written(written);
+        match written {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e.errno()),
+        }
     }
 
-    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
         //dbg!("OPEN");
-        let ino = self.store.open_file(ino);
+        if let Err(e) = self.check_access(_req, ino, permissions::open_mask(flags)) {
+            reply.error(e.errno());
+            return;
+        }
+
+        let handle = self.open_handle(ino, flags);
 
-        match ino {
-            Some(ino) => {
-                let flags = FOPEN_KEEP_CACHE;
-                reply.opened(ino, flags);
+        match handle {
+            Ok((fh, open_flags)) => {
+                reply.opened(fh, open_flags);
             }
-            None => {
-                reply.error(ENOENT);
+            Err(e) => {
+                reply.error(e.errno());
             }
         }
     }
@@ -86,7 +306,7 @@ impl Filesystem for FuseFS {
         &mut self,
         _req: &fuser::Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
@@ -94,9 +314,41 @@ impl Filesystem for FuseFS {
         reply: fuser::ReplyData,
     ) {
         //dbg!("READ");
-        let data = self.store.read_data(ino, offset, size).unwrap();
+        let data = match self.handles.get(fh) {
+            Ok(handle) if handle.readable() => self.store.read_data(ino, offset, size),
+            Ok(_) => Err(StoreError::BadFileHandle),
+            Err(e) => Err(e),
+        };
 
-        reply.data(&data);
+        match data {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        //dbg!("RELEASE");
+        let res = self.handles.release(fh).and_then(|handle| {
+            // An unlinked file is freed by the store once its last handle is released
+            if handle.dirty {
+                self.store.flush_file(handle.ino)?;
+            }
+            self.store.release_file(handle.ino)
+        });
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
     }
 
     fn create(
@@ -104,23 +356,150 @@ impl Filesystem for FuseFS {
         _req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
-        _mode: u32,
-        _umask: u32,
-        _flags: i32,
+        mode: u32,
+        umask: u32,
+        flags: i32,
         reply: fuser::ReplyCreate,
     ) {
         //dbg!("CREAT");
-        let attr = self
+        if let Err(e) = self.check_access(_req, parent, W_OK | X_OK) {
+            reply.error(e.errno());
+            return;
+        }
+
+        let name = name.to_owned();
+        let res = self
             .store
-            .create_file(
-                name.to_str().unwrap().to_owned(),
-                parent,
-                _req.uid(),
-                _req.gid(),
-            )
-            .unwrap();
+            .create_file(name.clone(), parent, _req.uid(), _req.gid(), mode, umask);
+
+        let attr = match res {
+            Ok(attr) => attr,
+            // Without O_EXCL a file created in the meantime is opened instead
+            Err(StoreError::AlreadyExists) if flags & O_EXCL == 0 => {
+                match self.store.lookup_file(name, parent) {
+                    Ok((_, info)) if info.attr.kind == FileType::Directory => {
+                        reply.error(EISDIR);
+                        return;
+                    }
+                    Ok((ino, info)) => {
+                        let mask = permissions::open_mask(flags);
+                        if let Err(e) = self.check_access(_req, ino, mask) {
+                            reply.error(e.errno());
+                            return;
+                        }
+                        info.attr
+                    }
+                    Err(e) => {
+                        reply.error(e.errno());
+                        return;
+                    }
+                }
+            }
+            Err(e) => {
+                reply.error(e.errno());
+                return;
+            }
+        };
+
+        // The kernel releases the handle it gets back here like any other
+        match self.open_handle(attr.ino, flags) {
+            Ok((fh, open_flags)) => reply.created(&TTL, &attr, 0, fh, open_flags),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: fuser::ReplyEmpty,
+    ) {
+        //dbg!("FLUSH");
+        let res = self.flush_handle(fh, false);
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
 
-        reply.created(&TTL, &attr, 0, 0, 0);
+    fn fsync(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        //dbg!("FSYNC");
+        let res = self.flush_handle(fh, true);
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        link_name: &std::ffi::OsStr,
+        target: &std::path::Path,
+        reply: fuser::ReplyEntry,
+    ) {
+        //dbg!("SYMLINK");
+        if let Err(e) = self.check_access(_req, parent, W_OK | X_OK) {
+            reply.error(e.errno());
+            return;
+        }
+
+        let attr = self.store.create_symlink(
+            link_name.to_owned(),
+            parent,
+            target.as_os_str().as_bytes(),
+            _req.uid(),
+            _req.gid(),
+        );
+
+        match attr {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        //dbg!("READLINK");
+        let target = self.store.read_link(ino);
+
+        match target {
+            Ok(target) => reply.data(&target),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &std::ffi::OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        //dbg!("LINK");
+        if let Err(e) = self.check_access(_req, newparent, W_OK | X_OK) {
+            reply.error(e.errno());
+            return;
+        }
+
+        let attr = self.store.link_file(ino, newparent, newname.to_owned());
+
+        match attr {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e.errno()),
+        }
     }
 
     // Dirs
@@ -132,16 +511,19 @@ impl Filesystem for FuseFS {
         reply: fuser::ReplyEntry,
     ) {
         //dbg!("LOOKUP");
-        let file = self
-            .store
-            .lookup_file(name.to_str().unwrap().to_owned(), parent);
+        if let Err(e) = self.check_access(_req, parent, X_OK) {
+            reply.error(e.errno());
+            return;
+        }
+
+        let file = self.store.lookup_file(name.to_owned(), parent);
 
         match file {
-            Some((_, info)) => {
+            Ok((_, info)) => {
                 reply.entry(&TTL, &info.attr, 0);
             }
-            None => {
-                reply.error(ENOENT);
+            Err(e) => {
+                reply.error(e.errno());
             }
         }
     }
@@ -151,36 +533,73 @@ impl Filesystem for FuseFS {
         _req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
-        _mode: u32,
-        _umask: u32,
+        mode: u32,
+        umask: u32,
         reply: fuser::ReplyEntry,
     ) {
         //dbg!("MKDIR");
-        let attr = self.store.create_dir(
-            name.to_str().unwrap().to_owned(),
-            parent,
-            _req.uid(),
-            _req.gid(),
-        );
+        if let Err(e) = self.check_access(_req, parent, W_OK | X_OK) {
+            reply.error(e.errno());
+            return;
+        }
+
+        let attr =
+            self.store
+                .create_dir(name.to_owned(), parent, _req.uid(), _req.gid(), mode, umask);
 
         match attr {
             Ok(attr) => reply.entry(&TTL, &attr, 0),
-            Err(_) => reply.error(ENOENT),
+            Err(e) => reply.error(e.errno()),
         }
     }
 
     fn rmdir(
         &mut self,
         _req: &fuser::Request<'_>,
-        _parent: u64,
+        parent: u64,
         name: &std::ffi::OsStr,
         reply: fuser::ReplyEmpty,
     ) {
         //dbg!("RMDIR");
-        let res = self.store.delete_dir(name.to_str().unwrap().to_owned());
+        if let Err(e) = self.check_remove(_req, parent, name) {
+            reply.error(e.errno());
+            return;
+        }
+
+        let res = self.store.delete_dir(parent, name.to_owned());
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        newparent: u64,
+        newname: &std::ffi::OsStr,
+        flags: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        //dbg!("RENAME");
+        if let Err(e) = self.check_rename(_req, parent, name, newparent, newname) {
+            reply.error(e.errno());
+            return;
+        }
+
+        let res = self.store.rename_entry(
+            parent,
+            name.to_owned(),
+            newparent,
+            newname.to_owned(),
+            flags,
+        );
+
         match res {
             Ok(_) => reply.ok(),
-            Err(e) => reply.error(e.raw_os_error().unwrap()),
+            Err(e) => reply.error(e.errno()),
         }
     }
 
@@ -193,7 +612,18 @@ impl Filesystem for FuseFS {
         mut reply: fuser::ReplyDirectory,
     ) {
         //dbg!("READDIR");
-        let entries = self.store.get_dir_entries(ino);
+        if let Err(e) = self.check_access(_req, ino, R_OK) {
+            reply.error(e.errno());
+            return;
+        }
+
+        let entries = match self.store.get_dir_entries(ino) {
+            Ok(entries) => entries,
+            Err(e) => {
+                reply.error(e.errno());
+                return;
+            }
+        };
 
         for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
             if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
@@ -203,14 +633,54 @@ impl Filesystem for FuseFS {
         reply.ok();
     }
 
+    fn readdirplus(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectoryPlus,
+    ) {
+        //dbg!("READDIRPLUS");
+        if let Err(e) = self.check_access(_req, ino, R_OK) {
+            reply.error(e.errno());
+            return;
+        }
+
+        // Only fetching as many entries as the reply can take, not the whole directory
+        let mut next = offset as usize;
+        'batches: loop {
+            let entries = match self.store.get_dir_entries_plus(ino, next, DIR_BATCH) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    reply.error(e.errno());
+                    return;
+                }
+            };
+            let fetched = entries.len();
+
+            for (name, attr) in entries {
+                if reply.add(attr.ino, (next + 1) as i64, name, &TTL, &attr, 0) {
+                    break 'batches;
+                }
+                next += 1;
+            }
+
+            if fetched < DIR_BATCH {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
     // Misc
     fn getattr(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
         //dbg!("GETATTR");
         let attr = self.store.get_file_attr(ino);
 
         match attr {
-            Some(attr) => reply.attr(&TTL, &attr),
-            None => reply.error(ENOENT),
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e.errno()),
         }
     }
 
@@ -218,14 +688,14 @@ impl Filesystem for FuseFS {
         &mut self,
         _req: &fuser::Request<'_>,
         ino: u64,
-        _mode: Option<u32>,
+        mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
         size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
         _crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
@@ -233,11 +703,204 @@ impl Filesystem for FuseFS {
         reply: fuser::ReplyAttr,
     ) {
         //dbg!("SETATTR");
-        let attr = self.store.set_file_attr(ino, uid, gid, size);
+        let changes = AttrChanges {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            ctime,
+        };
+
+        if self.permissions == PermissionsMode::FileSystem {
+            let checked = self.get_file_access(ino).and_then(|(attr, acl)| {
+                let (uid, groups) = (_req.uid(), request_groups(_req));
+                permissions::check_setattr(
+                    &attr,
+                    acl.as_ref(),
+                    uid,
+                    &groups,
+                    &changes,
+                    fh.is_some(),
+                )
+            });
+            if let Err(e) = checked {
+                reply.error(e.errno());
+                return;
+            }
+        }
+
+        let attr = self.store.set_file_attr(ino, changes);
 
         match attr {
-            Some(attr) => reply.attr(&TTL, &attr),
-            None => reply.error(ENOENT),
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn access(&mut self, _req: &fuser::Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        //dbg!("ACCESS");
+        let res = self.get_file_access(ino).and_then(|(attr, acl)| {
+            permissions::check_access(&attr, acl.as_ref(), _req.uid(), &request_groups(_req), mask)
+        });
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn statfs(&mut self, _req: &fuser::Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        //dbg!("STATFS");
+        let stats = self.store.stats();
+
+        match stats {
+            Ok(stats) => {
+                let blocks = stats.capacity.bytes / BLOCK_SIZE as u64;
+                let free_blocks =
+                    blocks.saturating_sub(stats.used_bytes.div_ceil(BLOCK_SIZE as u64));
+                let free_inodes = stats.capacity.inodes.saturating_sub(stats.used_inodes);
+
+                reply.statfs(
+                    blocks,
+                    free_blocks,
+                    free_blocks,
+                    stats.capacity.inodes,
+                    free_inodes,
+                    BLOCK_SIZE,
+                    NAME_MAX as u32,
+                    BLOCK_SIZE,
+                );
+            }
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        //dbg!("SETXATTR");
+        let res = name_to_string(name).and_then(|name| {
+            self.check_acl_owner(_req, ino, &name)?;
+            self.check_xattr_access(_req, ino, &name, W_OK)?;
+            self.store.set_xattr(ino, name, value, flags)
+        });
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        //dbg!("GETXATTR");
+        let value = name_to_string(name).and_then(|name| {
+            self.check_xattr_access(_req, ino, &name, R_OK)?;
+            self.store.get_xattr(ino, &name)
+        });
+
+        match value {
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn listxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        //dbg!("LISTXATTR");
+        // Listing the names needs no access to the file, like stat
+        let names = self.store.list_xattr(ino);
+
+        match names {
+            Ok(names) => {
+                // The list is sent as null-terminated names laid out back to back
+                let mut data = vec![];
+                for name in names {
+                    data.extend_from_slice(name.as_bytes());
+                    data.push(0);
+                }
+                reply_xattr(reply, size, &data);
+            }
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn removexattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        //dbg!("REMOVEXATTR");
+        let res = name_to_string(name).and_then(|name| {
+            self.check_acl_owner(_req, ino, &name)?;
+            self.check_xattr_access(_req, ino, &name, W_OK)?;
+            self.store.remove_xattr(ino, &name)
+        });
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+}
+
+// Groups of the caller, when the filesystem does the permission checks
+fn request_groups(req: &fuser::Request<'_>) -> Vec<u32> {
+    permissions::request_groups(req.pid(), req.gid())
+}
+
+// Extended attribute names are kept as UTF-8 by the stores
+fn name_to_string(name: &std::ffi::OsStr) -> StoreResult<String> {
+    name.to_str()
+        .map(str::to_owned)
+        .ok_or(StoreError::InvalidArgument)
+}
+
+// A size of 0 is the caller asking how big a buffer it needs
+fn reply_xattr(reply: fuser::ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+// Passes the invalidations reported by the store on to the kernel, until the store goes away.
+// Sent from outside the session loop, the kernel may wait on a pending request to apply them
+pub fn notify_invalidations(invalidations: mpsc::Receiver<Invalidation>, notifier: Notifier) {
+    for invalidation in invalidations {
+        let res = match &invalidation {
+            // Offset 0 and length 0 drop the whole page cache along with the attributes
+            Invalidation::Inode(ino) => notifier.inval_inode(*ino, 0, 0),
+            Invalidation::Entry(parent, name) => notifier.inval_entry(*parent, name),
+        };
+
+        if let Err(e) = res {
+            println!("Couldn't invalidate [{:?}]: [{}]", invalidation, e);
         }
     }
 }
@@ -0,0 +1,62 @@
+use crate::store::store::{StoreError, StoreResult};
+use libc::{O_ACCMODE, O_APPEND, O_DIRECT, O_RDONLY, O_WRONLY};
+use std::collections::HashMap;
+
+// State kept for a file from open or create until its release
+pub struct FileHandle {
+    pub ino: u64,
+    pub flags: i32,
+    // Set by writes not yet flushed to the store
+    pub dirty: bool,
+}
+
+impl FileHandle {
+    pub fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+
+    pub fn append(&self) -> bool {
+        self.flags & O_APPEND != 0
+    }
+
+    pub fn direct(&self) -> bool {
+        self.flags & O_DIRECT != 0
+    }
+}
+
+// Open files, keyed by the file handle given to the kernel
+#[derive(Default)]
+pub struct HandleTable {
+    handles: HashMap<u64, FileHandle>,
+    next_fh: u64,
+}
+
+impl HandleTable {
+    pub fn open(&mut self, ino: u64, flags: i32) -> u64 {
+        self.next_fh += 1;
+
+        let handle = FileHandle {
+            ino,
+            flags,
+            dirty: false,
+        };
+        self.handles.insert(self.next_fh, handle);
+        self.next_fh
+    }
+
+    pub fn get(&self, fh: u64) -> StoreResult<&FileHandle> {
+        self.handles.get(&fh).ok_or(StoreError::BadFileHandle)
+    }
+
+    pub fn get_mut(&mut self, fh: u64) -> StoreResult<&mut FileHandle> {
+        self.handles.get_mut(&fh).ok_or(StoreError::BadFileHandle)
+    }
+
+    pub fn release(&mut self, fh: u64) -> StoreResult<FileHandle> {
+        self.handles.remove(&fh).ok_or(StoreError::BadFileHandle)
+    }
+}
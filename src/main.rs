@@ -1,13 +1,16 @@
 mod fuse;
+mod handles;
+mod permissions;
 mod store;
 mod upgrade;
 mod exit;
 mod consts;
 
-use fuse::FuseFS;
-use fuser::MountOption;
-use std::{env, fs, io, sync::mpsc, thread};
-use store::store::StoreType;
+use fuse::{notify_invalidations, FuseFS};
+use fuser::{MountOption, Session};
+use permissions::PermissionsMode;
+use std::{env, fs, io, path::Path, sync::mpsc, thread};
+use store::store::{Invalidation, StoreType};
 use exit::{graceful_exit, handle_signal};
 use signal_hook::{consts::{SIGTERM, SIGINT}, iterator::Signals};
 use upgrade::start_graceful_upgrade;
@@ -21,9 +24,14 @@ async fn main() -> io::Result<()> {
 
     let store_type = get_store_from_env(consts::DEFAULT_STORE_TYPE);
     let mountpoint = get_mountpoint_from_env(consts::DEFAULT_MOUNTPOINT.to_string());
-    let file_system = FuseFS::new(&store_type);
+    let permissions = get_permissions_from_env(consts::DEFAULT_PERMISSIONS_MODE);
+    let (invalidations_tx, invalidations_rx) = mpsc::channel();
+    let file_system = FuseFS::new(&store_type, permissions, invalidations_tx);
 
-    let opts = &[MountOption::AllowOther, MountOption::AutoUnmount];
+    let mut opts = vec![MountOption::AllowOther, MountOption::AutoUnmount];
+    if permissions == PermissionsMode::Kernel {
+        opts.push(MountOption::DefaultPermissions);
+    }
 
     println!(
         "Mounting fuse filesystem on [{}] using mode [{:?}]...",
@@ -47,11 +55,25 @@ async fn main() -> io::Result<()> {
         }
     });
 
-    let _ = unmount_tx.send(fuser::mount2(file_system, mountpoint, opts));
+    let _ = unmount_tx.send(mount(file_system, &mountpoint, &opts, invalidations_rx));
 
     Ok(())
 }
 
+// Same as fuser::mount2, keeping a notifier to pass the store's invalidations to the kernel
+fn mount(
+    file_system: FuseFS,
+    mountpoint: &str,
+    opts: &[MountOption],
+    invalidations: mpsc::Receiver<Invalidation>,
+) -> io::Result<()> {
+    let mut session = Session::new(file_system, Path::new(mountpoint), opts)?;
+
+    let notifier = session.notifier();
+    thread::spawn(move || notify_invalidations(invalidations, notifier));
+
+    session.run()
+}
 
 fn get_store_from_env(default: StoreType) -> StoreType {
     let store_env = env::var("FUSEFS_STORE_TYPE");
@@ -83,6 +105,26 @@ fn get_store_from_env(default: StoreType) -> StoreType {
     }
 }
 
+fn get_permissions_from_env(default: PermissionsMode) -> PermissionsMode {
+    let permissions_env = env::var("FUSEFS_PERMISSIONS");
+
+    let permissions = match permissions_env.as_deref() {
+        Ok("fs") => PermissionsMode::FileSystem,
+        Ok("kernel") => PermissionsMode::Kernel,
+        Ok(str_permissions) => {
+            println!("Invalid permission checks: {}", str_permissions);
+            default
+        }
+        Err(_) => {
+            println!("No permission checks specified, using default");
+            default
+        }
+    };
+
+    println!("Proceeding with [{:?}] permission checks", permissions);
+    permissions
+}
+
 fn get_mountpoint_from_env(default: String) -> String {
     let mountpoint_env = env::var("FUSEFS_MOUNTPOINT");
     let mountpoint;
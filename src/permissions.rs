@@ -0,0 +1,131 @@
+use crate::store::{
+    acl::Acl,
+    store::{AttrChanges, StoreError, StoreResult},
+};
+use fuser::{FileAttr, FileType, TimeOrNow};
+use libc::{O_ACCMODE, O_RDWR, O_TRUNC, O_WRONLY, R_OK, S_ISVTX, W_OK, X_OK};
+
+// Who checks the request uid/gid against the file permissions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PermissionsMode {
+    // The filesystem does the checks itself
+    FileSystem,
+    // The kernel does the checks, the filesystem is mounted with DefaultPermissions
+    Kernel,
+}
+
+// Groups of the process behind a request: its primary group `gid` followed by the
+// supplementary groups listed in /proc, which the kernel doesn't pass along with requests.
+// If the process is already gone, only the primary group is known
+pub fn request_groups(pid: u32, gid: u32) -> Vec<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    let supplementary = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter_map(|group| group.parse::<u32>().ok());
+
+    let mut groups = vec![gid];
+    groups.extend(supplementary.filter(|&group| group != gid));
+    groups
+}
+
+// Fails unless `uid`, in `groups`, is granted every access in `mask` (R_OK, W_OK, X_OK) on
+// `attr`, going through the access ACL when the file has one
+pub fn check_access(
+    attr: &FileAttr,
+    acl: Option<&Acl>,
+    uid: u32,
+    groups: &[u32],
+    mask: i32,
+) -> StoreResult<()> {
+    if has_access(attr, acl, uid, groups, mask) {
+        Ok(())
+    } else {
+        Err(StoreError::PermissionDenied)
+    }
+}
+
+fn has_access(attr: &FileAttr, acl: Option<&Acl>, uid: u32, groups: &[u32], mask: i32) -> bool {
+    // Root can read and write anything, but only execute files someone can execute
+    if uid == 0 {
+        return mask & X_OK == 0 || attr.kind == FileType::Directory || attr.perm & 0o111 != 0;
+    }
+    if let Some(acl) = acl {
+        return acl.check(attr, uid, groups, mask);
+    }
+
+    let bits = if uid == attr.uid {
+        attr.perm >> 6
+    } else if groups.contains(&attr.gid) {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    };
+
+    (bits as i32) & mask == mask
+}
+
+// Access needed on a file to open it with `flags`
+pub fn open_mask(flags: i32) -> i32 {
+    let mask = match flags & O_ACCMODE {
+        O_WRONLY => W_OK,
+        O_RDWR => R_OK | W_OK,
+        _ => R_OK,
+    };
+
+    if flags & O_TRUNC != 0 {
+        mask | W_OK
+    } else {
+        mask
+    }
+}
+
+// In a sticky directory, entries can only be removed or renamed by their owner or the directory owner
+pub fn check_sticky(dir: &FileAttr, entry: &FileAttr, uid: u32) -> StoreResult<()> {
+    if uid == 0 || dir.perm & S_ISVTX as u16 == 0 || uid == dir.uid || uid == entry.uid {
+        Ok(())
+    } else {
+        Err(StoreError::NotPermitted)
+    }
+}
+
+// Mode and times can only be set by the owner, the owner only by root, and the group by the
+// owner to one of its groups. `has_handle` is set for ftruncate, checked when the file was opened
+pub fn check_setattr(
+    attr: &FileAttr,
+    acl: Option<&Acl>,
+    uid: u32,
+    groups: &[u32],
+    changes: &AttrChanges,
+    has_handle: bool,
+) -> StoreResult<()> {
+    let is_owner = uid == 0 || uid == attr.uid;
+
+    if changes.mode.is_some() && !is_owner {
+        return Err(StoreError::NotPermitted);
+    }
+    if changes.uid.is_some_and(|new_uid| new_uid != attr.uid) && uid != 0 {
+        return Err(StoreError::NotPermitted);
+    }
+    if let Some(new_gid) = changes.gid {
+        if uid != 0 && (uid != attr.uid || (!groups.contains(&new_gid) && new_gid != attr.gid)) {
+            return Err(StoreError::NotPermitted);
+        }
+    }
+    if changes.size.is_some() && !has_handle {
+        check_access(attr, acl, uid, groups, W_OK)?;
+    }
+
+    for time in [changes.atime, changes.mtime].into_iter().flatten() {
+        match time {
+            // Setting the times to now only takes write access, like touch does
+            TimeOrNow::Now if !is_owner => check_access(attr, acl, uid, groups, W_OK)?,
+            TimeOrNow::SpecificTime(_) if !is_owner => return Err(StoreError::NotPermitted),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
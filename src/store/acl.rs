@@ -0,0 +1,237 @@
+use super::store::{masked_perm, StoreError, StoreResult, Xattrs};
+use fuser::{FileAttr, FileType};
+
+pub const ACL_ACCESS: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+const ACL_VERSION: u32 = 2;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+#[derive(Clone, Copy, PartialEq)]
+struct AclEntry {
+    tag: u16,
+    perm: u16,
+    id: u32,
+}
+
+// POSIX ACL in the layout the kernel uses for the system.posix_acl_* xattrs,
+// a version header followed by little endian (tag, perm, id) entries
+#[derive(Clone)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    pub fn from_bytes(value: &[u8]) -> StoreResult<Acl> {
+        if value.len() < 4 || !(value.len() - 4).is_multiple_of(8) {
+            return Err(StoreError::InvalidArgument);
+        }
+        if u32::from_le_bytes(value[0..4].try_into().unwrap()) != ACL_VERSION {
+            return Err(StoreError::InvalidArgument);
+        }
+
+        let entries: Vec<_> = value[4..]
+            .chunks_exact(8)
+            .map(|entry| AclEntry {
+                tag: u16::from_le_bytes([entry[0], entry[1]]),
+                perm: u16::from_le_bytes([entry[2], entry[3]]),
+                id: u32::from_le_bytes(entry[4..8].try_into().unwrap()),
+            })
+            .collect();
+
+        let acl = Acl { entries };
+        acl.validate()?;
+        Ok(acl)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut value = ACL_VERSION.to_le_bytes().to_vec();
+
+        for entry in self.entries.iter() {
+            value.extend_from_slice(&entry.tag.to_le_bytes());
+            value.extend_from_slice(&entry.perm.to_le_bytes());
+            value.extend_from_slice(&entry.id.to_le_bytes());
+        }
+
+        value
+    }
+
+    // Exactly one owner, owning group and other entry, and a mask whenever named entries exist
+    fn validate(&self) -> StoreResult<()> {
+        let count = |tag| self.entries.iter().filter(|entry| entry.tag == tag).count();
+        let named = count(ACL_USER) + count(ACL_GROUP);
+
+        let mut ids: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.tag == ACL_USER || entry.tag == ACL_GROUP)
+            .map(|entry| (entry.tag, entry.id))
+            .collect();
+        ids.sort();
+        ids.dedup();
+
+        let valid = self.entries.iter().all(|entry| entry.perm & !0o7 == 0)
+            && count(ACL_USER_OBJ) == 1
+            && count(ACL_GROUP_OBJ) == 1
+            && count(ACL_OTHER) == 1
+            && count(ACL_MASK) <= 1
+            && (named == 0 || count(ACL_MASK) == 1)
+            && ids.len() == named
+            && named + count(ACL_MASK) + 3 == self.entries.len();
+
+        if valid {
+            Ok(())
+        } else {
+            Err(StoreError::InvalidArgument)
+        }
+    }
+
+    // An ACL with only the owner, owning group and other entries says nothing the mode doesn't
+    fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    fn perm(&self, tag: u16) -> u16 {
+        self.entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| entry.perm)
+            .unwrap_or(0)
+    }
+
+    // The mask stands for the group class when there is one, the owning group otherwise
+    fn group_class_tag(&self) -> u16 {
+        if self.entries.iter().any(|entry| entry.tag == ACL_MASK) {
+            ACL_MASK
+        } else {
+            ACL_GROUP_OBJ
+        }
+    }
+
+    // Permission bits reflecting the ACL
+    pub fn mode(&self) -> u16 {
+        self.perm(ACL_USER_OBJ) << 6 | self.perm(self.group_class_tag()) << 3 | self.perm(ACL_OTHER)
+    }
+
+    // Applies the permission bits to the owner, group class and other entries
+    fn set_mode(&mut self, perm: u16) {
+        let group_class = self.group_class_tag();
+
+        for entry in self.entries.iter_mut() {
+            match entry.tag {
+                ACL_USER_OBJ => entry.perm = perm >> 6 & 0o7,
+                ACL_OTHER => entry.perm = perm & 0o7,
+                tag if tag == group_class => entry.perm = perm >> 3 & 0o7,
+                _ => {}
+            }
+        }
+    }
+
+    // Same as set_mode, but only ever takes permissions away
+    fn restrict(&mut self, perm: u16) {
+        let mode = self.mode() & perm;
+        self.set_mode(mode);
+    }
+
+    // Access check algorithm from POSIX.1e, `groups` are all the groups of the caller
+    pub fn check(&self, attr: &FileAttr, uid: u32, groups: &[u32], mask: i32) -> bool {
+        let mask = mask as u16;
+        let grants = |perm: u16| perm & mask == mask;
+        let masked = |perm: u16| match self.entries.iter().find(|entry| entry.tag == ACL_MASK) {
+            Some(entry) => perm & entry.perm,
+            None => perm,
+        };
+
+        if uid == attr.uid {
+            return grants(self.perm(ACL_USER_OBJ));
+        }
+        let named_user = self
+            .entries
+            .iter()
+            .find(|entry| entry.tag == ACL_USER && entry.id == uid);
+        if let Some(entry) = named_user {
+            return grants(masked(entry.perm));
+        }
+
+        let mut groups = self.entries.iter().filter(|entry| {
+            (entry.tag == ACL_GROUP_OBJ && groups.contains(&attr.gid))
+                || (entry.tag == ACL_GROUP && groups.contains(&entry.id))
+        });
+        if let Some(first) = groups.next() {
+            return std::iter::once(first)
+                .chain(groups)
+                .any(|entry| grants(masked(entry.perm)));
+        }
+
+        grants(self.perm(ACL_OTHER))
+    }
+}
+
+// Sets one of the ACL xattrs, an access ACL also sets the permission bits. Like other
+// filesystems, an access ACL equivalent to the mode is folded into it and not kept
+pub fn set_acl(
+    xattrs: &mut Xattrs,
+    attr: &mut FileAttr,
+    name: String,
+    value: &[u8],
+) -> StoreResult<()> {
+    let acl = Acl::from_bytes(value)?;
+
+    if name == ACL_DEFAULT {
+        if attr.kind != FileType::Directory {
+            return Err(StoreError::PermissionDenied);
+        }
+        xattrs.insert(name, value.to_vec());
+        return Ok(());
+    }
+
+    attr.perm = (attr.perm & !0o777) | acl.mode();
+    if acl.is_minimal() {
+        xattrs.remove(&name);
+    } else {
+        xattrs.insert(name, value.to_vec());
+    }
+
+    Ok(())
+}
+
+// Keeps the access ACL in line with permission bits set through chmod
+pub fn chmod_acl(xattrs: &mut Xattrs, perm: u16) {
+    if let Some(value) = xattrs.get_mut(ACL_ACCESS) {
+        if let Ok(mut acl) = Acl::from_bytes(value) {
+            acl.set_mode(perm);
+            *value = acl.to_bytes();
+        }
+    }
+}
+
+// Permission bits and ACLs a new entry gets from the mode, umask and its parent's default ACL.
+// When the parent has a default ACL it replaces the umask, and directories inherit it too
+pub fn inherit_acl(parent: &Xattrs, kind: FileType, mode: u32, umask: u32) -> (u16, Xattrs) {
+    let mut xattrs = Xattrs::new();
+    let default = parent
+        .get(ACL_DEFAULT)
+        .and_then(|value| Acl::from_bytes(value).ok());
+
+    let Some(default) = default else {
+        return (masked_perm(mode, umask), xattrs);
+    };
+
+    let mut acl = default.clone();
+    acl.restrict(mode as u16 & 0o777);
+    let perm = (mode & 0o7000) as u16 | acl.mode();
+
+    if !acl.is_minimal() {
+        xattrs.insert(ACL_ACCESS.to_owned(), acl.to_bytes());
+    }
+    if kind == FileType::Directory {
+        xattrs.insert(ACL_DEFAULT.to_owned(), default.to_bytes());
+    }
+
+    (perm, xattrs)
+}
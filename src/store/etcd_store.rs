@@ -1,568 +1,2100 @@
-use super::store::{FileInfo, Store};
-use etcd_client::Client;
+use super::acl::{chmod_acl, inherit_acl, ACL_ACCESS};
+use super::quota::Quotas;
+use super::store::{
+    check_name, set_xattr, AttrChanges, Capacity, FileInfo, Invalidation, Store, StoreError,
+    StoreResult, StoreStats, Xattrs,
+};
+use bincode::Options;
+use etcd_client::{
+    Client, Compare, CompareOp, DeleteOptions, EventType, GetOptions, KeyValue, PutOptions, Txn,
+    TxnOp, TxnOpResponse, WatchOptions,
+};
 use fuser::{FileAttr, FileType};
-use serde::{Deserialize, Serialize};
+use libc::{O_TRUNC, RENAME_EXCHANGE, RENAME_NOREPLACE};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    collections::HashMap,
-    io::{self, ErrorKind},
-    sync::mpsc,
-    time::SystemTime,
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    future::Future,
+    ops::Range,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        mpsc, Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 type Ino = u64;
+// Directory entries, a file shows up once for each of its hard links
+type Dirs = HashMap<Ino, Vec<(OsString, Ino)>>;
+// An inode along with its record as stored in etcd, and the revision it was last modified at
+type Record = (Ino, Vec<u8>, i64);
 const DEFAULT_ETCD_ENDPOINT: &str = "localhost:2379";
+// etcd's default limit on the number of operations in a transaction (--max-txn-ops)
+const MAX_TXN_OPS: usize = 128;
+// Keys read at a time when scanning etcd on startup
+const SCAN_BATCH: i64 = 256;
+// File content is split in chunks of this size, each under its own key
+const CHUNK_SIZE: u64 = 64 << 10;
+// Prefix of the chunk keys, inode keys are all digits and sort before them
+const CHUNK_KEYS: &str = "data/";
+// Chunks written in a single transaction, well under etcd's 1.5 MiB request limit
+const MAX_TXN_CHUNKS: usize = 16;
+const ROOT_INO: Ino = 1;
+// Inode counter shared by all the mounts of the cluster
+const INO_COUNTER_KEY: &str = "ino_counter";
+// Directory entries each have a key "entries/<parent>/<name>" holding the inode they link to,
+// so that etcd refuses two mounts creating the same name. They sort after the chunk keys
+const ENTRY_KEYS: &str = "entries/";
+// Records start with the version of the format they are written in. Earlier versions wrote
+// YAML, which starts with text rather than a control character
+const RECORD_FORMAT: u8 = 1;
+// Format the whole store was migrated to, missing until the first migration. Stores in format
+// 1 have their records in RECORD_FORMAT, format 2 adds the entry keys
+const STORE_FORMAT: u8 = 2;
+const FORMAT_KEY: &str = "format_version";
+// Pause before catching up with etcd when the watch on its changes breaks
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+// Files open on a mount each have a key "open/<ino>/<lease>" under the mount's lease, so that
+// the other mounts don't free them when they are unlinked. The keys go away with the lease if
+// the mount stops without releasing them
+const OPEN_KEYS: &str = "open/";
+// Seconds the lease outlives the last renewal
+const LEASE_TTL: i64 = 10;
+const LEASE_RENEWAL: Duration = Duration::from_secs(LEASE_TTL as u64 / 3);
+// Pause between checks for the new lease while an expired one is replaced
+const LEASE_POLL: Duration = Duration::from_millis(100);
+// Matches etcd's default --quota-backend-bytes
+const DEFAULT_CAPACITY: Capacity = Capacity {
+    bytes: 2 << 30,
+    inodes: 1 << 20,
+};
 
 pub struct EtcdStore {
+    // Highest inode seen by this mount, other mounts allocate from the same counter
     ino_count: Ino,
     client: Client,
-    dirs: HashMap<Ino, Vec<Ino>>,
-    ino_to_name: HashMap<Ino, String>,
+    // Shared with the task following the changes of other mounts
+    index: Arc<Mutex<Index>>,
+    // Told about the deletions found outside of the watch
+    invalidations: mpsc::Sender<Invalidation>,
+    // Shared with the task renewing the lease, which puts the keys of the open files back
+    // under a new one if it expires
+    open_handles: Arc<Mutex<HashMap<Ino, u32>>>,
+    // Lease of the keys of the files open on this mount, 0 while an expired one is replaced
+    lease: Arc<AtomicI64>,
+}
+
+// What the mount knows of the files in etcd, kept up to date with its own commits and with
+// the changes of the other mounts coming through the watch
+struct Index {
+    dirs: Dirs,
+    // Entries of each file, to find the parent of a directory without going through them all
+    links: HashMap<Ino, Vec<(Ino, OsString)>>,
+    // Revision each file was indexed at, kept once it is deleted so that changes coming in
+    // late or twice are recognized
+    revisions: HashMap<Ino, i64>,
+    // Usage of every file in etcd, so the limits hold across all mounts
+    quotas: Quotas,
+    // Owner and size each file is charged for
+    charges: HashMap<Ino, (u32, u32, u64)>,
+}
+
+// Transaction built by commit_checked: the comparisons it depends on, its operations, and
+// the files it leaves behind for the index, `None` when deleted
+#[derive(Default)]
+struct Change {
+    compares: Vec<Compare>,
+    ops: Vec<TxnOp>,
+    files: Vec<(Ino, Option<FileData>)>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 struct FileData {
-    name: String,
+    #[serde(with = "name_format")]
+    name: OsString,
+    attr: FileAttr,
+    parent: Option<Ino>,
+    // Hard links to the inode besides (parent, name)
+    #[serde(with = "links_format")]
+    links: Vec<(Ino, OsString)>,
+    xattrs: Xattrs,
+}
+
+// Names are written as raw bytes, they don't have to be valid UTF-8
+mod name_format {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(name: &OsString, serializer: S) -> Result<S::Ok, S::Error> {
+        name.as_bytes().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OsString, D::Error> {
+        Vec::<u8>::deserialize(deserializer).map(OsString::from_vec)
+    }
+}
+
+mod links_format {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        links: &[(Ino, OsString)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        links
+            .iter()
+            .map(|(parent, name)| (*parent, name.as_bytes()))
+            .collect::<Vec<_>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(Ino, OsString)>, D::Error> {
+        let links = Vec::<(Ino, Vec<u8>)>::deserialize(deserializer)?;
+
+        Ok(links
+            .into_iter()
+            .map(|(parent, name)| (parent, OsString::from_vec(name)))
+            .collect())
+    }
+}
+
+// Records written in YAML by earlier versions, the file content along with the rest
+#[derive(Deserialize)]
+struct YamlFileData {
+    name: YamlName,
     attr: FileAttr,
     parent: Option<Ino>,
+    #[serde(default)]
+    links: Vec<(Ino, YamlName)>,
+    #[serde(default)]
+    xattrs: Xattrs,
+    #[serde(default)]
     data: Vec<u8>,
 }
 
+// Names were written as plain strings when they were valid UTF-8, as raw bytes otherwise
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum YamlName {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<YamlName> for OsString {
+    fn from(name: YamlName) -> Self {
+        match name {
+            YamlName::Text(name) => name.into(),
+            YamlName::Bytes(bytes) => OsString::from_vec(bytes),
+        }
+    }
+}
+
+impl YamlFileData {
+    // Splits the record into the file and its content
+    fn into_parts(self) -> (FileData, Vec<u8>) {
+        let file_data = FileData {
+            name: self.name.into(),
+            attr: self.attr,
+            parent: self.parent,
+            links: self
+                .links
+                .into_iter()
+                .map(|(parent, name)| (parent, name.into()))
+                .collect(),
+            xattrs: self.xattrs,
+        };
+
+        (file_data, self.data)
+    }
+}
+
+impl FileData {
+    fn move_link(&mut self, parent: Ino, name: &OsStr, new_parent: Ino, new_name: OsString) {
+        if self.parent == Some(parent) && self.name == name {
+            self.parent = Some(new_parent);
+            self.name = new_name;
+        } else if let Some(link) = self
+            .links
+            .iter_mut()
+            .find(|(link_parent, link_name)| *link_parent == parent && link_name == name)
+        {
+            *link = (new_parent, new_name);
+        }
+    }
+
+    // Link count changes of a directory, from subdirectories coming and going
+    fn add_links(&mut self, delta: i64) {
+        self.attr.nlink = (self.attr.nlink as i64 + delta) as u32;
+        self.attr.ctime = SystemTime::now();
+    }
+
+    // Another link takes over as (parent, name) when the main one goes away
+    fn remove_link(&mut self, parent: Ino, name: &OsStr) {
+        if self.parent == Some(parent) && self.name == name {
+            if let Some((link_parent, link_name)) = self.links.pop() {
+                self.parent = Some(link_parent);
+                self.name = link_name;
+            }
+        } else {
+            self.links
+                .retain(|(link_parent, link_name)| !(*link_parent == parent && link_name == name));
+        }
+
+        if self.attr.kind == FileType::Directory {
+            self.attr.nlink = 0;
+        } else {
+            self.attr.nlink = self.attr.nlink.saturating_sub(1);
+        }
+        self.attr.ctime = SystemTime::now();
+    }
+}
+
+impl Index {
+    fn new(quotas: Quotas) -> Index {
+        Index {
+            dirs: Dirs::new(),
+            links: HashMap::new(),
+            revisions: HashMap::new(),
+            quotas,
+            charges: HashMap::new(),
+        }
+    }
+
+    // Brings the entries of `ino` in line with the file as committed at `revision` by any
+    // mount, `None` once it was deleted. Returns the entries that were added or removed, or
+    // nothing if the index was already at that revision
+    fn apply(
+        &mut self,
+        ino: Ino,
+        file_data: Option<&FileData>,
+        revision: i64,
+    ) -> Option<Vec<(Ino, OsString)>> {
+        if self
+            .revisions
+            .get(&ino)
+            .is_some_and(|&indexed| indexed >= revision)
+        {
+            return None;
+        }
+        self.revisions.insert(ino, revision);
+
+        // A file is charged to its owner for as long as its record is in etcd
+        if let Some((uid, gid, size)) = self.charges.remove(&ino) {
+            self.quotas.charge(uid, gid, -(size as i64), -1);
+        }
+        if let Some(file_data) = file_data {
+            let attr = &file_data.attr;
+            self.quotas.charge(attr.uid, attr.gid, attr.size as i64, 1);
+            self.charges.insert(ino, (attr.uid, attr.gid, attr.size));
+        }
+
+        let entries: Vec<(Ino, OsString)> = match file_data {
+            Some(file_data) if file_data.attr.nlink > 0 => file_data
+                .parent
+                .map(|parent| (parent, file_data.name.clone()))
+                .into_iter()
+                .chain(file_data.links.iter().cloned())
+                .collect(),
+            _ => vec![],
+        };
+        let indexed = self.links.remove(&ino).unwrap_or_default();
+        let mut changed = vec![];
+
+        for (parent, name) in indexed.iter().filter(|entry| !entries.contains(entry)) {
+            if let Some(children) = self.dirs.get_mut(parent) {
+                children.retain(|(child_name, child)| !(*child == ino && child_name == name));
+            }
+            changed.push((*parent, name.clone()));
+        }
+        // Entries already known keep their place in the listing
+        for (parent, name) in entries.iter().filter(|entry| !indexed.contains(entry)) {
+            let children = self.dirs.entry(*parent).or_default();
+            children.push((name.clone(), ino));
+            changed.push((*parent, name.clone()));
+        }
+        if !entries.is_empty() {
+            self.links.insert(ino, entries);
+        }
+
+        match file_data {
+            Some(file_data)
+                if file_data.attr.kind == FileType::Directory && file_data.attr.nlink > 0 =>
+            {
+                self.dirs.entry(ino).or_default();
+            }
+            _ => {
+                self.dirs.remove(&ino);
+            }
+        }
+
+        Some(changed)
+    }
+
+    fn parent_of(&self, ino: Ino) -> Option<Ino> {
+        self.links.get(&ino)?.first().map(|&(parent, _)| parent)
+    }
+}
+
+impl Change {
+    fn put(&mut self, ino: Ino, file_data: &FileData) -> StoreResult<()> {
+        self.ops.push(put_op(ino, file_data)?);
+        self.files.push((ino, Some(file_data.clone())));
+        Ok(())
+    }
+
+    fn delete(&mut self, ino: Ino) {
+        self.ops.extend(delete_ops(ino));
+        self.files.push((ino, None));
+    }
+}
+
 impl Store for EtcdStore {
     type Ino = Ino;
 
-    fn new() -> io::Result<Self> {
+    fn new(invalidations: mpsc::Sender<Invalidation>) -> StoreResult<Self> {
         let endpoint = get_etcd_endpoint_from_env(DEFAULT_ETCD_ENDPOINT.to_string());
 
-        let (tx, rx) = mpsc::channel();
-        tokio::spawn(async move {
+        let client = block_on(async move {
             println!("Connecting to Etcd on endpoint [{}]", endpoint);
             let mut client = Client::connect([endpoint], None)
                 .await
-                .expect("Couldn't connect to server");
-
-            let res = client.member_list().await;
-            match res {
-                Ok(res) => {
-                    println!("Connected to Etcd, members list:");
-                    res.members().iter().for_each(|m| {
-                        println!("Etcd member: [{:?}]", m);
-                    });
-
-                    let root_dir_attr = FileAttr {
-                        ino: 1,
-                        size: 0,
-                        blocks: 0,
-                        atime: SystemTime::now(),
-                        mtime: SystemTime::now(),
-                        ctime: SystemTime::now(),
-                        crtime: SystemTime::now(),
-                        kind: FileType::Directory,
-                        perm: 0o755,
-                        nlink: 2,
-                        uid: 0,
-                        gid: 0,
-                        rdev: 0,
-                        flags: 0,
-                        blksize: 512,
-                    };
-                    let root_dir = FileData {
-                        name: ".".to_owned(),
-                        attr: root_dir_attr,
-                        parent: None,
-                        data: vec![],
-                    };
-                    let str_root_dir = serde_yaml::to_string(&root_dir).unwrap();
-                    let res = client.put("1", str_root_dir, None).await;
-                    match res {
-                        Ok(_) => {
-                            let _ = tx.send(client);
+                .map_err(backend_error)?;
+
+            let res = client.member_list().await.map_err(backend_error)?;
+            println!("Connected to Etcd, members list:");
+            res.members().iter().for_each(|m| {
+                println!("Etcd member: [{:?}]", m);
+            });
+
+            Ok(client)
+        });
+        match client {
+            Ok(client) => {
+                let mut store = EtcdStore {
+                    index: Arc::new(Mutex::new(Index::new(Quotas::from_env(DEFAULT_CAPACITY)))),
+                    client,
+                    invalidations,
+                    open_handles: Arc::new(Mutex::new(HashMap::new())),
+                    lease: Arc::new(AtomicI64::new(0)),
+                    ino_count: ROOT_INO,
+                };
+
+                let format = store.get_format_version()?;
+                if format > STORE_FORMAT {
+                    return Err(StoreError::Backend(format!(
+                        "etcd store is in format [{}], only up to [{}] is supported",
+                        format, STORE_FORMAT
+                    )));
+                }
+
+                // Picking up the files left by previous runs, the root is only created once
+                let (has_root, revision) = store.rebuild_index()?;
+                // Records still in YAML were migrated by the rebuild
+                if format < STORE_FORMAT {
+                    store.migrate_entries()?;
+                    let put = TxnOp::put(FORMAT_KEY, STORE_FORMAT.to_string(), None);
+                    store.commit_ops(vec![put])?;
+                }
+                if !has_root {
+                    store.create_root()?;
+                }
+
+                // Anything committed after the scan comes through the watch
+                store.watch_changes(revision + 1);
+                store.lease.store(store.grant_lease()?, Ordering::SeqCst);
+                store.renew_lease();
+
+                Ok(store)
+            }
+            Err(e) => {
+                println!("Couldn't connect to Etcd: [{}]", e);
+                Err(StoreError::Backend("couldn't connect to etcd".to_owned()))
+            }
+        }
+    }
+
+    fn create_file(
+        &mut self,
+        name: OsString,
+        parent: Ino,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+        umask: u32,
+    ) -> StoreResult<fuser::FileAttr> {
+        self.check_new_entry(parent, &name)?;
+        self.index().quotas.check(uid, gid, 0, 1)?;
+
+        let new_ino = self.allocate_ino()?;
+
+        let file_attr = self.commit_checked(|change| {
+            // The ACL inherited from the parent is the one it has when the file is added
+            let parent_xattrs = self.read_checked(parent, change)?.xattrs;
+            self.claim_entry(parent, &name, new_ino, change)?;
+            let (perm, xattrs) = inherit_acl(&parent_xattrs, FileType::RegularFile, mode, umask);
+            let file_attr = create_attr(new_ino, uid, gid, FileType::RegularFile, perm);
+            let file_data = FileData {
+                name: name.clone(),
+                attr: file_attr,
+                parent: Some(parent),
+                links: vec![],
+                xattrs,
+            };
+
+            change.put(new_ino, &file_data)?;
+            Ok(file_attr)
+        })?;
+
+        Ok(file_attr)
+    }
+
+    fn delete_file(&mut self, parent: Ino, name: OsString) -> StoreResult<()> {
+        self.commit_checked(|change| {
+            let file_ino = self.read_entry(parent, &name, change)?;
+            let mut file_data = self.read_checked(file_ino, change)?;
+            if file_data.attr.kind == FileType::Directory {
+                return Err(StoreError::IsADirectory);
+            }
+            file_data.remove_link(parent, &name);
+
+            change.ops.push(delete_entry_op(parent, &name));
+            self.put_unlinked(file_ino, &file_data, change)
+        })
+    }
+
+    fn create_symlink(
+        &mut self,
+        name: OsString,
+        parent: Ino,
+        target: &[u8],
+        uid: u32,
+        gid: u32,
+    ) -> StoreResult<FileAttr> {
+        self.check_new_entry(parent, &name)?;
+        self.index()
+            .quotas
+            .check(uid, gid, target.len() as i64, 1)?;
+
+        let new_ino = self.allocate_ino()?;
+
+        let mut file_attr = create_attr(new_ino, uid, gid, FileType::Symlink, 0o777);
+        file_attr.size = target.len() as u64;
+
+        let file_data = FileData {
+            name: name.clone(),
+            attr: file_attr,
+            parent: Some(parent),
+            links: vec![],
+            xattrs: Xattrs::new(),
+        };
+
+        self.commit_checked(|change| {
+            // The parent must still be there when the symlink is added to it
+            self.read_checked(parent, change)?;
+            self.claim_entry(parent, &name, new_ino, change)?;
+
+            // The link target is kept as the symlink's content
+            change.put(new_ino, &file_data)?;
+            change.ops.extend(content_ops(new_ino, target));
+            Ok(())
+        })?;
+
+        Ok(file_attr)
+    }
+
+    fn read_link(&self, ino: Ino) -> StoreResult<Vec<u8>> {
+        let file_data = self.get_file_data(ino)?;
+
+        if file_data.attr.kind != FileType::Symlink {
+            return Err(StoreError::InvalidArgument);
+        }
+
+        self.read_content(ino, 0..file_data.attr.size)
+    }
+
+    fn lookup_file(&self, name: OsString, parent: Ino) -> StoreResult<(Ino, FileInfo)> {
+        if !self.index().dirs.contains_key(&parent) {
+            return Err(StoreError::NotFound);
+        }
+
+        let ino = match name.to_str() {
+            Some(".") => parent,
+            Some("..") => self.parent_dir(parent),
+            _ => self.find_child(parent, &name).ok_or(StoreError::NotFound)?,
+        };
+        let file_data = self.get_file_data(ino)?;
+
+        // Reporting the entry the inode was reached through, it may have other links
+        let file_info = FileInfo {
+            attr: file_data.attr,
+            name,
+            parent: Some(parent),
+        };
+
+        Ok((ino, file_info))
+    }
+
+    fn read_data(&self, ino: Ino, offset: i64, size: u32) -> StoreResult<Vec<u8>> {
+        let start = offset as u64;
+        let (_, content) = self.get_file_content(ino, start..start + size as u64)?;
+
+        Ok(content)
+    }
+
+    fn write_data(&mut self, ino: Ino, data: &[u8], offset: i64, append: bool) -> StoreResult<u32> {
+        self.update_file_data(ino, |file_data, ops| {
+            let (uid, gid) = (file_data.attr.uid, file_data.attr.gid);
+            let size = file_data.attr.size;
+
+            // Another client may have grown the file, appending goes after what etcd holds
+            let start = if append { size } else { offset as u64 };
+            let end = start + data.len() as u64;
+            let growth = end.saturating_sub(size) as i64;
+            self.index().quotas.check(uid, gid, growth, 0)?;
+
+            // Only the chunks the write falls in are rewritten. Writing past the end of the
+            // file leaves the chunks in between missing, they read as a zero-filled hole
+            let chunks = self.get_chunks(ino, chunk_indexes(&(start..end)))?;
+            for (index, chunk) in write_chunks(chunks, data, start) {
+                ops.push(TxnOp::put(chunk_key(ino, index), chunk, None));
+            }
+            file_data.attr.size = size.max(end);
+
+            Ok(())
+        })?;
+
+        Ok(data.len() as u32)
+    }
+
+    fn open_file(&mut self, ino: Ino, flags: i32) -> StoreResult<Ino> {
+        if flags & O_TRUNC != 0 {
+            let changes = AttrChanges {
+                size: Some(0),
+                ..Default::default()
+            };
+            self.set_file_attr(ino, changes)?;
+        } else {
+            self.get_file_data(ino)?;
+        }
+        // The first handle tells the other mounts the file is open here
+        if !self.open_handles().contains_key(&ino) {
+            self.mark_open(ino)?;
+        }
+        *self.open_handles().entry(ino).or_insert(0) += 1;
+
+        Ok(ino)
+    }
+
+    fn release_file(&mut self, ino: Ino) -> StoreResult<()> {
+        let mut open_handles = self.open_handles();
+        let Some(count) = open_handles.get_mut(&ino) else {
+            return Ok(());
+        };
+
+        *count -= 1;
+        if *count > 0 {
+            return Ok(());
+        }
+        open_handles.remove(&ino);
+        drop(open_handles);
+        let lease = self.current_lease()?;
+        self.commit_ops(vec![TxnOp::delete(open_key(ino, lease), None)])?;
+
+        // The last handle on an unlinked file is what finally frees it, once no other mount
+        // has it open either
+        self.free_unlinked(ino)
+    }
+
+    fn flush_file(&mut self, _ino: Ino) -> StoreResult<()> {
+        // Writes are committed to etcd as they come in, nothing is buffered
+        Ok(())
+    }
+
+    fn link_file(
+        &mut self,
+        ino: Ino,
+        new_parent: Ino,
+        new_name: OsString,
+    ) -> StoreResult<FileAttr> {
+        self.check_new_entry(new_parent, &new_name)?;
+
+        let file_data = self.commit_checked(|change| {
+            let mut file_data = self.read_checked(ino, change)?;
+            // Directories can't be hard linked
+            if file_data.attr.kind == FileType::Directory {
+                return Err(StoreError::NotPermitted);
+            }
+
+            // The new parent must still be there when the link is added to it
+            self.read_checked(new_parent, change)?;
+            self.claim_entry(new_parent, &new_name, ino, change)?;
+            file_data.links.push((new_parent, new_name.clone()));
+            file_data.attr.nlink += 1;
+            file_data.attr.ctime = SystemTime::now();
+
+            change.put(ino, &file_data)?;
+            Ok(file_data)
+        })?;
+
+        Ok(file_data.attr)
+    }
+
+    fn create_dir(
+        &mut self,
+        name: OsString,
+        parent: Ino,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+        umask: u32,
+    ) -> StoreResult<FileAttr> {
+        self.check_new_entry(parent, &name)?;
+        self.index().quotas.check(uid, gid, 0, 1)?;
+
+        let new_ino = self.allocate_ino()?;
+
+        let file_attr = self.commit_checked(|change| {
+            let mut parent_data = self.read_checked(parent, change)?;
+            self.claim_entry(parent, &name, new_ino, change)?;
+            let (perm, xattrs) = inherit_acl(&parent_data.xattrs, FileType::Directory, mode, umask);
+            let file_attr = create_attr(new_ino, uid, gid, FileType::Directory, perm);
+            let file_data = FileData {
+                name: name.clone(),
+                attr: file_attr,
+                parent: Some(parent),
+                links: vec![],
+                xattrs,
+            };
+
+            // The new directory's ".." is one more link to the parent
+            parent_data.add_links(1);
+            change.put(new_ino, &file_data)?;
+            change.put(parent, &parent_data)?;
+            Ok(file_attr)
+        })?;
+
+        Ok(file_attr)
+    }
+
+    fn get_dir_entries(&self, ino: Ino) -> StoreResult<Vec<(u64, FileType, OsString)>> {
+        let children = self
+            .index()
+            .dirs
+            .get(&ino)
+            .cloned()
+            .ok_or(StoreError::NotADirectory)?;
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".into()),
+            (self.parent_dir(ino), FileType::Directory, "..".into()),
+        ];
+
+        let child_inos: Vec<_> = children.iter().map(|&(_, child_ino)| child_ino).collect();
+        let files_data = self.get_files_data(&child_inos)?;
+
+        for ((name, child_ino), file_data) in children.iter().zip(files_data) {
+            if let Some(file_data) = file_data {
+                entries.push((*child_ino, file_data.attr.kind, name.clone()));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn get_dir_entries_plus(
+        &self,
+        ino: Ino,
+        offset: usize,
+        count: usize,
+    ) -> StoreResult<Vec<(OsString, FileAttr)>> {
+        // Children another mount deleted are dropped from the index as they are fetched, the
+        // batch is then taken again so that the offsets stay in line with the index
+        loop {
+            let entries = self.dir_batch(ino, offset, count)?;
+            let inos: Vec<_> = entries.iter().map(|&(_, ino)| ino).collect();
+            let files_data = self.get_files_data(&inos)?;
+
+            if files_data.iter().all(Option::is_some) {
+                return Ok(entries
+                    .into_iter()
+                    .zip(files_data.into_iter().flatten())
+                    .map(|((name, _), file_data)| (name, file_data.attr))
+                    .collect());
+            }
+            // Only children leave the index, the directory itself is gone
+            let is_dot = |name: &OsString| name == "." || name == "..";
+            if entries
+                .iter()
+                .zip(&files_data)
+                .any(|((name, _), file_data)| file_data.is_none() && is_dot(name))
+            {
+                return Err(StoreError::NotFound);
+            }
+        }
+    }
+
+    fn get_xattr(&self, ino: Ino, name: &str) -> StoreResult<Vec<u8>> {
+        let file_data = self.get_file_data(ino)?;
+
+        file_data
+            .xattrs
+            .get(name)
+            .cloned()
+            .ok_or(StoreError::NoAttribute)
+    }
+
+    fn set_xattr(&mut self, ino: Ino, name: String, value: &[u8], flags: i32) -> StoreResult<()> {
+        self.update_file_data(ino, |file_data, _| {
+            set_xattr(
+                &mut file_data.xattrs,
+                &mut file_data.attr,
+                name.clone(),
+                value,
+                flags,
+            )?;
+            file_data.attr.ctime = SystemTime::now();
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    fn list_xattr(&self, ino: Ino) -> StoreResult<Vec<String>> {
+        let file_data = self.get_file_data(ino)?;
+
+        Ok(file_data.xattrs.into_keys().collect())
+    }
+
+    fn remove_xattr(&mut self, ino: Ino, name: &str) -> StoreResult<()> {
+        self.update_file_data(ino, |file_data, _| {
+            file_data
+                .xattrs
+                .remove(name)
+                .ok_or(StoreError::NoAttribute)?;
+            file_data.attr.ctime = SystemTime::now();
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    fn get_file_attr(&self, ino: Ino) -> StoreResult<FileAttr> {
+        Ok(self.get_file_data(ino)?.attr)
+    }
+
+    fn get_file_access(&self, ino: Ino) -> StoreResult<(FileAttr, Option<Vec<u8>>)> {
+        let mut file_data = self.get_file_data(ino)?;
+
+        Ok((file_data.attr, file_data.xattrs.remove(ACL_ACCESS)))
+    }
+
+    fn delete_dir(&mut self, parent: Ino, name: OsString) -> StoreResult<()> {
+        self.commit_checked(|change| {
+            let dir_ino = self.read_entry(parent, &name, change)?;
+            let dir_data = self.read_checked(dir_ino, change)?;
+            if dir_data.attr.kind != FileType::Directory {
+                return Err(StoreError::NotADirectory);
+            }
+            // Nothing can be added to the directory while it goes away
+            self.check_empty_dir(dir_ino, change)?;
+
+            change.ops.push(delete_entry_op(parent, &name));
+            change.delete(dir_ino);
+            self.add_dir_links(parent, -1, change)
+        })
+    }
+
+    fn rename_entry(
+        &mut self,
+        parent: Ino,
+        name: OsString,
+        new_parent: Ino,
+        new_name: OsString,
+        flags: u32,
+    ) -> StoreResult<()> {
+        let noreplace = flags & RENAME_NOREPLACE != 0;
+        let exchange = flags & RENAME_EXCHANGE != 0;
+        if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0 || (noreplace && exchange) {
+            return Err(StoreError::InvalidArgument);
+        }
+        check_name(&new_name)?;
+
+        self.commit_checked(|change| {
+            let ino = self.read_entry(parent, &name, change)?;
+            let target = self.find_entry(new_parent, &new_name, change)?;
+            // Even a link to the same file is there already
+            if target.is_some() && noreplace {
+                return Err(StoreError::AlreadyExists);
+            }
+            // Both names are links to the same file, there's nothing to do
+            if target == Some(ino) {
+                return Ok(());
+            }
+
+            let mut file_data = self.read_checked(ino, change)?;
+
+            // A directory can't be moved inside its own subtree
+            if file_data.attr.kind == FileType::Directory && self.is_ancestor(ino, new_parent) {
+                return Err(StoreError::InvalidArgument);
+            }
+
+            let mut target_kind = None;
+            match target {
+                Some(target_ino) => {
+                    let mut target_data = self.read_checked(target_ino, change)?;
+                    target_kind = Some(target_data.attr.kind);
+                    if exchange {
+                        if target_data.attr.kind == FileType::Directory
+                            && self.is_ancestor(target_ino, parent)
+                        {
+                            return Err(StoreError::InvalidArgument);
                         }
-                        Err(e) => {
-                            panic!("Couldn't create root dir: [{}]", e);
+
+                        target_data.move_link(new_parent, &new_name, parent, name.clone());
+                        target_data.attr.ctime = SystemTime::now();
+                        change.put(target_ino, &target_data)?;
+                    } else {
+                        match (file_data.attr.kind, target_data.attr.kind) {
+                            (FileType::Directory, FileType::Directory) => {
+                                self.check_empty_dir(target_ino, change)?;
+                            }
+                            (FileType::Directory, kind) if kind != FileType::Directory => {
+                                return Err(StoreError::NotADirectory);
+                            }
+                            (kind, FileType::Directory) if kind != FileType::Directory => {
+                                return Err(StoreError::IsADirectory);
+                            }
+                            _ => {}
                         }
+
+                        target_data.remove_link(new_parent, &new_name);
+                        self.put_unlinked(target_ino, &target_data, change)?;
                     }
                 }
-                Err(e) => {
-                    println!("Couldn't get Etcd member list: [{}]", e);
+                None if exchange => return Err(StoreError::NotFound),
+                None => {}
+            }
+
+            file_data.move_link(parent, &name, new_parent, new_name.clone());
+            file_data.attr.ctime = SystemTime::now();
+            change.put(ino, &file_data)?;
+
+            // The entry being replaced is overwritten, the one being exchanged swapped
+            match target {
+                Some(target_ino) if exchange => {
+                    change.ops.push(put_entry_op(parent, &name, target_ino))
+                }
+                _ => change.ops.push(delete_entry_op(parent, &name)),
+            }
+            change.ops.push(put_entry_op(new_parent, &new_name, ino));
+
+            // Subdirectories moving around change the link count of their parents
+            let mut nlink_deltas: HashMap<Ino, i64> = HashMap::new();
+            if file_data.attr.kind == FileType::Directory {
+                *nlink_deltas.entry(parent).or_default() -= 1;
+                *nlink_deltas.entry(new_parent).or_default() += 1;
+            }
+            if target_kind == Some(FileType::Directory) {
+                *nlink_deltas.entry(new_parent).or_default() -= 1;
+                if exchange {
+                    *nlink_deltas.entry(parent).or_default() += 1;
+                }
+            }
+            for (dir_ino, delta) in nlink_deltas {
+                if delta != 0 {
+                    self.add_dir_links(dir_ino, delta, change)?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn set_file_attr(&mut self, ino: Ino, changes: AttrChanges) -> StoreResult<FileAttr> {
+        let (file_data, _) = self.update_file_data(ino, |file_data, ops| {
+            let (uid, gid) = (file_data.attr.uid, file_data.attr.gid);
+
+            if let Some(size) = changes.size {
+                let growth = size as i64 - file_data.attr.size as i64;
+                self.index().quotas.check(uid, gid, growth, 0)?;
+
+                // Shrinking drops the tail, growing leaves a hole up to the new size
+                if size < file_data.attr.size {
+                    ops.extend(self.truncate_ops(ino, size)?);
+                }
+            }
+            changes.apply(&mut file_data.attr);
+            if changes.mode.is_some() {
+                chmod_acl(&mut file_data.xattrs, file_data.attr.perm);
+            }
+
+            Ok(())
+        })?;
+
+        Ok(file_data.attr)
+    }
+
+    fn stats(&self) -> StoreResult<StoreStats> {
+        let mut client = self.client.clone();
+        let db_size = block_on(async move {
+            let res = client.status().await.map_err(backend_error)?;
+            Ok(res.db_size() as u64)
+        })?;
+
+        // What etcd takes on disk can be well over the content itself
+        let index = self.index();
+        let usage = index.quotas.usage();
+        Ok(StoreStats {
+            capacity: index.quotas.capacity(),
+            used_bytes: usage.bytes.max(db_size),
+            used_inodes: usage.inodes,
+        })
+    }
+}
+
+impl EtcdStore {
+    // Takes the next inode from the shared counter, retrying when another mount got there first.
+    // Inodes found on startup are never handed out again, even if the counter is behind
+    fn allocate_ino(&mut self) -> StoreResult<Ino> {
+        loop {
+            let (counter, revision) = self.get_ino_counter()?;
+            let ino = counter.max(self.ino_count) + 1;
+
+            // A missing key has a mod revision of 0, so the first allocation creates it
+            let unchanged = Compare::mod_revision(INO_COUNTER_KEY, CompareOp::Equal, revision);
+            let put = TxnOp::put(INO_COUNTER_KEY, ino.to_string(), None);
+            if self.commit_ops_if(vec![unchanged], vec![put])?.is_some() {
+                self.ino_count = ino;
+                return Ok(ino);
+            }
+        }
+    }
+
+    fn get_ino_counter(&self) -> StoreResult<(Ino, i64)> {
+        let mut client = self.client.clone();
+
+        block_on(async move {
+            let res = client
+                .get(INO_COUNTER_KEY, None)
+                .await
+                .map_err(backend_error)?;
+
+            match res.kvs().first() {
+                Some(kv) => kv
+                    .value_str()
+                    .ok()
+                    .and_then(|value| value.parse::<Ino>().ok())
+                    .map(|counter| (counter, kv.mod_revision()))
+                    .ok_or(StoreError::Backend("invalid inode counter".to_owned())),
+                None => Ok((0, 0)),
+            }
+        })
+    }
+
+    // Rebuilds the directory index, inode counter and usage from the files found in etcd,
+    // migrating the records still in YAML on the way, and frees the unlinked ones left behind.
+    // Tells whether the root was among them, and the revision they were read at
+    fn rebuild_index(&mut self) -> StoreResult<(bool, i64)> {
+        let (records, revision) = self.scan_records()?;
+        let has_root = records.iter().any(|&(ino, ..)| ino == ROOT_INO);
+
+        let mut files_data = Vec::with_capacity(records.len());
+        for (ino, record, mod_revision) in records {
+            let file_data = if is_yaml_record(&record) {
+                self.migrate_record(ino, &record)?
+            } else {
+                decode_file_data(&record)?
+            };
+            files_data.push((ino, file_data, mod_revision));
+        }
+
+        let mut index = self.index.lock().unwrap();
+
+        for (ino, file_data, mod_revision) in files_data.iter() {
+            // Inodes of orphans are still taken, their keys must not be reused
+            self.ino_count = self.ino_count.max(*ino);
+            // Unlinked files that were still open when the previous run stopped aren't listed
+            index.apply(*ino, Some(file_data), *mod_revision);
+        }
+
+        // Entries were listed in creation order before the restart
+        for children in index.dirs.values_mut() {
+            children.sort_by_key(|&(_, ino)| ino);
+        }
+        drop(index);
+
+        println!("Found [{}] files in Etcd", files_data.len());
+
+        // Files still open when a mount stopped are kept until its lease runs out
+        for (ino, file_data, _) in files_data.iter() {
+            if file_data.attr.nlink > 0 {
+                continue;
+            }
+            if let Err(e) = self.free_unlinked(*ino) {
+                println!("Couldn't free unlinked file [{}]: [{}]", ino, e);
+            }
+        }
+
+        Ok((has_root, revision))
+    }
+
+    // Creates the root, unless another mount starting at the same time got there first. Its
+    // root is indexed instead, along with whatever it already put in it
+    fn create_root(&self) -> StoreResult<()> {
+        let root_dir = FileData {
+            name: "/".into(),
+            attr: create_attr(ROOT_INO, 0, 0, FileType::Directory, 0o755),
+            parent: None,
+            links: vec![],
+            xattrs: Xattrs::new(),
+        };
+        let missing = Compare::create_revision(ROOT_INO.to_string(), CompareOp::Equal, 0);
+        let put = put_op(ROOT_INO, &root_dir)?;
+
+        let (root_dir, revision) = match self.commit_ops_if(vec![missing], vec![put])? {
+            Some(revision) => (root_dir, revision),
+            None => self.get_file_data_revision(ROOT_INO)?,
+        };
+        self.index().apply(ROOT_INO, Some(&root_dir), revision);
+
+        Ok(())
+    }
+
+    fn scan_records(&self) -> StoreResult<(Vec<Record>, i64)> {
+        let mut client = self.client.clone();
+
+        block_on(async move { fetch_records(&mut client).await })
+    }
+
+    // Follows the changes committed by every mount from `revision` on, keeping the index up
+    // to date and reporting which kernel caches they made stale. Changes this mount committed
+    // are already indexed and skipped. If the watch breaks, or etcd was compacted past what it
+    // has seen, the index catches up from a new scan and the watch starts over from there
+    fn watch_changes(&self, revision: i64) {
+        let mut client = self.client.clone();
+        let index = self.index.clone();
+        let invalidations = self.invalidations.clone();
+
+        tokio::spawn(async move {
+            let mut revision = revision;
+
+            loop {
+                let reason = follow_changes(&mut client, &index, &invalidations, revision).await;
+                println!(
+                    "Stopped following Etcd: [{}], catching up in [{:?}]",
+                    reason, WATCH_RETRY_DELAY
+                );
+
+                loop {
+                    tokio::time::sleep(WATCH_RETRY_DELAY).await;
+
+                    match catch_up(&mut client, &index, &invalidations).await {
+                        Ok(scanned) => {
+                            revision = scanned + 1;
+                            break;
+                        }
+                        Err(e) => println!("Couldn't catch up with Etcd: [{}]", e),
+                    }
+                }
+            }
+        });
+    }
+
+    fn grant_lease(&self) -> StoreResult<i64> {
+        let mut client = self.client.clone();
+
+        block_on(async move {
+            let res = client.lease_grant(LEASE_TTL, None).await;
+            res.map(|res| res.id()).map_err(backend_error)
+        })
+    }
+
+    // Keeps the lease of the open keys alive for as long as the mount runs, replacing it if it
+    // expires anyway
+    fn renew_lease(&self) {
+        let mut client = self.client.clone();
+        let open_handles = self.open_handles.clone();
+        let lease = self.lease.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = keep_lease_alive(&mut client, lease.load(Ordering::SeqCst)).await {
+                    println!(
+                        "Stopped renewing the Etcd lease: [{}], retrying in [{:?}]",
+                        e, WATCH_RETRY_DELAY
+                    );
+                    tokio::time::sleep(WATCH_RETRY_DELAY).await;
+                    continue;
+                }
+
+                // Files are only opened and released again once their keys are under the new one
+                println!("Etcd lease expired, granting a new one");
+                lease.store(0, Ordering::SeqCst);
+                while let Err(e) = replace_lease(&mut client, &open_handles, &lease).await {
+                    println!(
+                        "Couldn't replace the Etcd lease: [{}], retrying in [{:?}]",
+                        e, WATCH_RETRY_DELAY
+                    );
+                    tokio::time::sleep(WATCH_RETRY_DELAY).await;
+                }
+            }
+        });
+    }
+
+    // Lease to keep the open keys under, waiting while an expired one is replaced
+    fn current_lease(&self) -> StoreResult<i64> {
+        let deadline = Instant::now() + Duration::from_secs(LEASE_TTL as u64);
+
+        loop {
+            let lease = self.lease.load(Ordering::SeqCst);
+            if lease != 0 {
+                return Ok(lease);
+            }
+            if Instant::now() > deadline {
+                return Err(StoreError::Backend(
+                    "no etcd lease for open files".to_owned(),
+                ));
+            }
+            thread::sleep(LEASE_POLL);
+        }
+    }
+
+    fn open_handles(&self) -> MutexGuard<'_, HashMap<Ino, u32>> {
+        self.open_handles.lock().unwrap()
+    }
+
+    fn index(&self) -> MutexGuard<'_, Index> {
+        self.index.lock().unwrap()
+    }
+
+    // A new entry needs an existing parent directory, the name is only claimed on commit
+    fn check_new_entry(&self, parent: Ino, name: &OsStr) -> StoreResult<()> {
+        check_name(name)?;
+
+        if !self.index().dirs.contains_key(&parent) {
+            return Err(StoreError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    fn find_child(&self, parent: Ino, name: &OsStr) -> Option<Ino> {
+        self.index()
+            .dirs
+            .get(&parent)?
+            .iter()
+            .find(|(child_name, _)| child_name == name)
+            .map(|&(_, ino)| ino)
+    }
+
+    // Directories have a single parent, the root being its own
+    fn parent_dir(&self, ino: Ino) -> Ino {
+        self.index().parent_of(ino).unwrap_or(ino)
+    }
+
+    // Walks up the parents of `ino` looking for `ancestor`, `ino` counts as its own ancestor
+    fn is_ancestor(&self, ancestor: Ino, ino: Ino) -> bool {
+        let mut current = Some(ino);
+        while let Some(ino) = current {
+            if ino == ancestor {
+                return true;
+            }
+            current = self.index().parent_of(ino);
+        }
+
+        false
+    }
+
+    // Applies a link count change to a directory
+    fn add_dir_links(&self, ino: Ino, delta: i64, change: &mut Change) -> StoreResult<()> {
+        let mut file_data = self.read_checked(ino, change)?;
+        file_data.add_links(delta);
+
+        change.put(ino, &file_data)
+    }
+
+    // Persists a file that just lost a link, it is only deleted once the last link is gone
+    // and nobody has it open anymore
+    fn put_unlinked(&self, ino: Ino, file_data: &FileData, change: &mut Change) -> StoreResult<()> {
+        if self.is_freed(ino, file_data, change)? {
+            change.delete(ino);
+            Ok(())
+        } else {
+            change.put(ino, file_data)
+        }
+    }
+
+    // A file without links is freed unless a mount has it open. Other mounts can't open it
+    // before the transaction goes through
+    fn is_freed(&self, ino: Ino, file_data: &FileData, change: &mut Change) -> StoreResult<bool> {
+        if file_data.attr.nlink > 0 || self.open_handles().contains_key(&ino) {
+            return Ok(false);
+        }
+
+        let prefix = open_prefix(ino);
+        if self.count_keys(prefix.clone())? > 0 {
+            return Ok(false);
+        }
+        change
+            .compares
+            .push(Compare::create_revision(prefix, CompareOp::Equal, 0).with_prefix());
+
+        Ok(true)
+    }
+
+    // Deletes a file without links, unless a mount has it open
+    fn free_unlinked(&self, ino: Ino) -> StoreResult<()> {
+        self.commit_checked(|change| {
+            let file_data = self.read_checked(ino, change)?;
+            if self.is_freed(ino, &file_data, change)? {
+                change.delete(ino);
+            }
+            Ok(())
+        })
+    }
+
+    // Tells the other mounts the file is open here, as long as it wasn't freed in the meantime
+    fn mark_open(&self, ino: Ino) -> StoreResult<()> {
+        loop {
+            let lease = self.current_lease()?;
+            let exists = Compare::mod_revision(ino.to_string(), CompareOp::Greater, 0);
+
+            match self.commit_ops_if(vec![exists], vec![open_put_op(ino, lease)]) {
+                Ok(Some(_)) => return Ok(()),
+                Ok(None) => return Err(StoreError::NotFound),
+                // The lease expired in the meantime and was replaced
+                Err(_) if self.lease.load(Ordering::SeqCst) != lease => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn get_file_data(&self, ino: Ino) -> StoreResult<FileData> {
+        Ok(self.get_file_data_revision(ino)?.0)
+    }
+
+    // Also returns the revision the file was last modified at, to detect concurrent updates
+    fn get_file_data_revision(&self, ino: Ino) -> StoreResult<(FileData, i64)> {
+        let mut client = self.client.clone();
+
+        block_on(async move {
+            let res = client
+                .get(ino.to_string(), None)
+                .await
+                .map_err(backend_error)?;
+
+            match res.kvs().first() {
+                Some(kv) => {
+                    decode_file_data(kv.value()).map(|file_data| (file_data, kv.mod_revision()))
+                }
+                None => Err(StoreError::NotFound),
+            }
+        })
+    }
+
+    // Up to `count` entries of the directory from `offset` on, the dot entries first. The
+    // parent is only looked up when they are in the batch
+    fn dir_batch(
+        &self,
+        ino: Ino,
+        offset: usize,
+        count: usize,
+    ) -> StoreResult<Vec<(OsString, Ino)>> {
+        let dots = 2;
+        let mut entries: Vec<(OsString, Ino)> = vec![];
+        if offset < dots {
+            let dot_entries = [(".".into(), ino), ("..".into(), self.parent_dir(ino))];
+            entries.extend(dot_entries.into_iter().skip(offset));
+        }
+
+        // Only the entries of the batch are copied out of the index
+        let index = self.index();
+        let children = index.dirs.get(&ino).ok_or(StoreError::NotADirectory)?;
+        entries.extend(
+            children
+                .iter()
+                .skip(offset.saturating_sub(dots))
+                .take(count)
+                .cloned(),
+        );
+        entries.truncate(count);
+
+        Ok(entries)
+    }
+
+    // Fetches several files at once, in as few transactions as etcd allows. Files another mount
+    // deleted before the watch caught up come back as `None`, and are dropped from the index
+    fn get_files_data(&self, inos: &[Ino]) -> StoreResult<Vec<Option<FileData>>> {
+        let mut files_data = Vec::with_capacity(inos.len());
+
+        for chunk in inos.chunks(MAX_TXN_OPS) {
+            let mut client = self.client.clone();
+            let ops: Vec<_> = chunk
+                .iter()
+                .map(|ino| TxnOp::get(ino.to_string(), None))
+                .collect();
+
+            let (chunk_data, revision) = block_on(async move {
+                let res = client
+                    .txn(Txn::new().and_then(ops))
+                    .await
+                    .map_err(backend_error)?;
+                let revision = res.header().map_or(0, |header| header.revision());
+
+                let chunk_data: Vec<Option<FileData>> = res
+                    .op_responses()
+                    .into_iter()
+                    .map(|op| match op {
+                        TxnOpResponse::Get(res) => res
+                            .kvs()
+                            .first()
+                            .map(|kv| decode_file_data(kv.value()))
+                            .transpose(),
+                        _ => Err(StoreError::Backend("unexpected etcd response".to_owned())),
+                    })
+                    .collect::<StoreResult<_>>()?;
+
+                Ok((chunk_data, revision))
+            })?;
+
+            let mut index = self.index();
+            for (ino, file_data) in chunk.iter().zip(&chunk_data) {
+                if file_data.is_none() {
+                    let changed = index.apply(*ino, None, revision);
+                    report_changes(&self.invalidations, *ino, changed);
                 }
             }
-        });
+            drop(index);
 
-        let client = rx.recv();
-        match client {
-            Ok(client) => {
-                let mut dirs = HashMap::new();
-                let mut ino_to_name = HashMap::new();
+            files_data.extend(chunk_data);
+        }
 
-                dirs.insert(1, vec![]);
-                ino_to_name.insert(1, ".".to_owned());
+        Ok(files_data)
+    }
 
-                return Ok(EtcdStore {
-                    dirs,
-                    client,
-                    ino_to_name,
-                    ino_count: 1,
-                });
+    fn put_file_data(&self, ino: Ino, file_data: &FileData) -> StoreResult<()> {
+        self.commit_checked(|change| change.put(ino, file_data))
+    }
+
+    // Reads a file the transaction being built depends on, the transaction only goes through
+    // if the file is still unchanged by then
+    fn read_checked(&self, ino: Ino, change: &mut Change) -> StoreResult<FileData> {
+        let (file_data, revision) = self.get_file_data_revision(ino)?;
+        change.compares.push(Compare::mod_revision(
+            ino.to_string(),
+            CompareOp::Equal,
+            revision,
+        ));
+
+        Ok(file_data)
+    }
+
+    // Builds a transaction with `build` and commits it, starting over if another client
+    // modified any of the files `build` read with read_checked in between, so that concurrent
+    // updates are never lost. The files it wrote are indexed at the revision it committed at
+    fn commit_checked<T>(
+        &self,
+        mut build: impl FnMut(&mut Change) -> StoreResult<T>,
+    ) -> StoreResult<T> {
+        loop {
+            let mut change = Change::default();
+            let res = build(&mut change)?;
+
+            if let Some(revision) = self.commit_ops_if(change.compares, change.ops)? {
+                let mut index = self.index();
+                for (ino, file_data) in change.files {
+                    index.apply(ino, file_data.as_ref(), revision);
+                }
+                return Ok(res);
             }
-            Err(_) => return Err(ErrorKind::BrokenPipe.into()),
         }
     }
 
-    fn create_file(
-        &mut self,
-        name: String,
-        parent: Ino,
-        uid: u32,
-        gid: u32,
-    ) -> io::Result<fuser::FileAttr> {
-        self.ino_count += 1;
-
-        let new_ino = self.ino_count;
+    // Reads the file, applies `update` and writes it back along with the operations `update`
+    // added
+    fn update_file_data<T>(
+        &self,
+        ino: Ino,
+        mut update: impl FnMut(&mut FileData, &mut Vec<TxnOp>) -> StoreResult<T>,
+    ) -> StoreResult<(FileData, T)> {
+        self.commit_checked(|change| {
+            let mut file_data = self.read_checked(ino, change)?;
+            let res = update(&mut file_data, &mut change.ops)?;
+            change.put(ino, &file_data)?;
+
+            Ok((file_data, res))
+        })
+    }
 
-        let file_attr = create_attr(new_ino, uid, gid, FileType::RegularFile);
-        let file_data = FileData {
-            name: name.clone(),
-            attr: file_attr.clone(),
-            parent: Some(parent),
-            data: vec![],
-        };
+    // Fetches the chunks of `ino` in `indexes` with a single range request, chunks that were
+    // never written are left out. Content only ever changes along with the file's own key,
+    // checking the revision of that key is enough to catch concurrent updates
+    fn get_chunks(&self, ino: Ino, indexes: Range<u64>) -> StoreResult<HashMap<u64, Vec<u8>>> {
+        if indexes.is_empty() {
+            return Ok(HashMap::new());
+        }
 
-        let (tx, rx) = mpsc::channel();
         let mut client = self.client.clone();
+        let from = chunk_key(ino, indexes.start);
+        let options = GetOptions::new().with_range(chunk_key(ino, indexes.end));
 
-        tokio::spawn(async move {
-            let payload = serde_yaml::to_string(&file_data).unwrap();
-            let res = client.put(new_ino.to_string(), payload, None).await;
+        block_on(async move {
+            let res = client
+                .get(from, Some(options))
+                .await
+                .map_err(backend_error)?;
 
-            match res {
-                Ok(_) => tx.send(Ok(())),
-                Err(_) => tx.send(Err(())),
-            }
-        });
+            decode_chunks(res.kvs())
+        })
+    }
 
-        let res = rx.recv();
-        match res {
-            Ok(_) => {
-                self.ino_to_name.insert(new_ino, name);
-                self.dirs.get_mut(&parent).unwrap().push(new_ino);
-                return Ok(file_attr);
-            }
-            Err(_) => Err(ErrorKind::BrokenPipe.into()),
+    // Assembles the content of `ino` in `range` from the chunks it spans
+    fn read_content(&self, ino: Ino, range: Range<u64>) -> StoreResult<Vec<u8>> {
+        if range.is_empty() {
+            return Ok(vec![]);
         }
-    }
 
-    fn delete_file(&mut self, name: String) -> io::Result<()> {
-        let file_ino = self.ino_to_name.iter().find(|(_, n)| **n == name);
+        let chunks = self.get_chunks(ino, chunk_indexes(&range))?;
+        Ok(assemble_content(chunks, range))
+    }
 
-        if let Some((&file_ino, _)) = file_ino {
-            let (tx, rx) = mpsc::channel();
-            let mut client = self.client.clone();
+    // Reads the file along with its content in `range` in a single transaction, so that both
+    // are from the same revision. The content stops at the end of the file
+    fn get_file_content(&self, ino: Ino, range: Range<u64>) -> StoreResult<(FileData, Vec<u8>)> {
+        let mut client = self.client.clone();
+        let indexes = chunk_indexes(&range);
+        let options = GetOptions::new().with_range(chunk_key(ino, indexes.end));
+        let ops = vec![
+            TxnOp::get(ino.to_string(), None),
+            TxnOp::get(chunk_key(ino, indexes.start), Some(options)),
+        ];
 
-            tokio::spawn(async move {
-                let res = client.delete(file_ino.to_string(), None).await;
+        let (file_data, chunks) = block_on(async move {
+            let res = client
+                .txn(Txn::new().and_then(ops))
+                .await
+                .map_err(backend_error)?;
+
+            let mut responses = res.op_responses().into_iter();
+            let (Some(TxnOpResponse::Get(record)), Some(TxnOpResponse::Get(chunks))) =
+                (responses.next(), responses.next())
+            else {
+                return Err(StoreError::Backend("unexpected etcd response".to_owned()));
+            };
+            let record = record.kvs().first().ok_or(StoreError::NotFound)?;
+
+            Ok((
+                decode_file_data(record.value())?,
+                decode_chunks(chunks.kvs())?,
+            ))
+        })?;
+
+        let size = file_data.attr.size;
+        let content = assemble_content(chunks, range.start.min(size)..range.end.min(size));
+        Ok((file_data, content))
+    }
 
-                match res {
-                    Ok(_) => tx.send(Ok(())),
-                    Err(_) => tx.send(Err(())),
-                }
-            });
+    // Operations dropping the content of `ino` past `size`
+    fn truncate_ops(&self, ino: Ino, size: u64) -> StoreResult<Vec<TxnOp>> {
+        let (dropped, cut) = truncation(size);
+        let mut ops = vec![delete_chunks_op(ino, dropped)];
 
-            let res = rx.recv();
-            match res {
-                Ok(_) => {
-                    self.ino_to_name.remove(&file_ino);
-                    self.dirs.iter_mut().for_each(|(_, v)| {
-                        let index_to_remove = v.iter().position(|&i| i == file_ino);
-                        if let Some(index_to_remove) = index_to_remove {
-                            v.remove(index_to_remove);
-                        }
-                    });
-                    return Ok(());
-                }
-                Err(_) => Err(ErrorKind::BrokenPipe.into()),
+        // The chunk the file now ends in only keeps what comes before the end
+        if let Some((last, len)) = cut {
+            if let Some(mut chunk) = self.get_chunks(ino, last..last + 1)?.remove(&last) {
+                chunk.truncate(len);
+                ops.push(TxnOp::put(chunk_key(ino, last), chunk, None));
             }
-        } else {
-            return Ok(());
         }
-    }
 
-    fn lookup_file(&self, name: String, parent: Ino) -> Option<(Ino, FileInfo)> {
-        let file_ino = self.ino_to_name.iter().find(|(_, n)| *n == &name);
-        match file_ino {
-            Some((&ino, _)) => {
-                let (tx, rx) = mpsc::channel();
-                let mut client = self.client.clone();
+        Ok(ops)
+    }
 
-                tokio::spawn(async move {
-                    let res = client.get(ino.to_string(), None).await;
+    // Looks up an entry in etcd rather than in the index, for a transaction that depends on it:
+    // the transaction only goes through if the entry is still the same, or still missing
+    fn find_entry(
+        &self,
+        parent: Ino,
+        name: &OsStr,
+        change: &mut Change,
+    ) -> StoreResult<Option<Ino>> {
+        let mut client = self.client.clone();
+        let key = entry_key(parent, name);
+        let get_key = key.clone();
+
+        let (ino, revision) = block_on(async move {
+            let res = client.get(get_key, None).await.map_err(backend_error)?;
+
+            match res.kvs().first() {
+                Some(kv) => kv
+                    .value_str()
+                    .ok()
+                    .and_then(|value| value.parse::<Ino>().ok())
+                    .map(|ino| (Some(ino), kv.mod_revision()))
+                    .ok_or(StoreError::Backend("invalid directory entry".to_owned())),
+                None => Ok((None, 0)),
+            }
+        })?;
+        // A missing key has a mod revision of 0, so this also checks it is still missing
+        change
+            .compares
+            .push(Compare::mod_revision(key, CompareOp::Equal, revision));
 
-                    match res {
-                        Ok(res) => {
-                            res.kvs().iter().for_each(|kv| {
-                                let ino = kv.key_str().unwrap();
-                                let file_data = kv.value_str().unwrap();
+        Ok(ino)
+    }
 
-                                let file_data =
-                                    serde_yaml::from_str::<FileData>(file_data).unwrap();
+    fn read_entry(&self, parent: Ino, name: &OsStr, change: &mut Change) -> StoreResult<Ino> {
+        self.find_entry(parent, name, change)?
+            .ok_or(StoreError::NotFound)
+    }
 
-                                let file_info = super::store::FileInfo {
-                                    attr: file_data.attr,
-                                    name: file_data.name,
-                                    parent: Some(parent),
-                                };
+    // Adds the entry for `ino`, unless another mount already took the name
+    fn claim_entry(
+        &self,
+        parent: Ino,
+        name: &OsStr,
+        ino: Ino,
+        change: &mut Change,
+    ) -> StoreResult<()> {
+        if self.find_entry(parent, name, change)?.is_some() {
+            return Err(StoreError::AlreadyExists);
+        }
 
-                                tx.send(Some((ino.parse::<Ino>().unwrap(), file_info)))
-                                    .unwrap();
-                            });
-                        }
-                        Err(_) => {
-                            tx.send(None).unwrap();
-                        }
-                    }
-                });
+        change.ops.push(put_entry_op(parent, name, ino));
+        Ok(())
+    }
 
-                return rx.recv().unwrap();
-            }
-            None => None,
+    // Fails if the directory has entries in etcd, and keeps other mounts from adding any
+    // before the transaction goes through
+    fn check_empty_dir(&self, ino: Ino, change: &mut Change) -> StoreResult<()> {
+        let prefix = entries_prefix(ino);
+        if self.count_keys(prefix.clone())? > 0 {
+            return Err(StoreError::NotEmpty);
         }
+        // Compared over a range without any key, the create revision is 0
+        change
+            .compares
+            .push(Compare::create_revision(prefix, CompareOp::Equal, 0).with_prefix());
+
+        Ok(())
     }
 
-    fn read_data(&self, ino: Ino, offset: i64, size: u32) -> io::Result<Vec<u8>> {
-        let (tx, rx) = mpsc::channel();
+    fn count_keys(&self, prefix: Vec<u8>) -> StoreResult<i64> {
         let mut client = self.client.clone();
+        let options = GetOptions::new().with_prefix().with_count_only();
 
-        tokio::spawn(async move {
-            let res = client.get(ino.to_string(), None).await;
-
-            match res {
-                Ok(res) => {
-                    res.kvs().iter().for_each(|kv| {
-                        let value = kv.value_str().unwrap();
-                        let file_data: FileData = serde_yaml::from_str(value).unwrap();
+        block_on(async move {
+            let res = client.get(prefix, Some(options)).await;
+            res.map(|res| res.count()).map_err(backend_error)
+        })
+    }
 
-                        let data = file_data.data;
-                        let data_len = data.len() as i64;
+    // Stores from before the entry keys only had the entries in the records, their keys are
+    // written from the index. A migration cut short starts over on next startup
+    fn migrate_entries(&self) -> StoreResult<()> {
+        let mut ops: Vec<_> = self
+            .index()
+            .dirs
+            .iter()
+            .flat_map(|(&parent, children)| {
+                children
+                    .iter()
+                    .map(move |(name, ino)| put_entry_op(parent, name, *ino))
+            })
+            .collect();
 
-                        let start = offset as usize;
-                        let end = (offset + size as i64) as usize;
+        while !ops.is_empty() {
+            let batch = ops.drain(..ops.len().min(MAX_TXN_OPS)).collect();
+            self.commit_ops(batch)?;
+        }
 
-                        let data = if end > data_len as usize {
-                            data[start..].to_vec()
-                        } else {
-                            data[start..end].to_vec()
-                        };
+        Ok(())
+    }
 
-                        tx.send(Ok(data)).unwrap();
-                    });
-                }
-                Err(_) => {
-                    tx.send(Err(ErrorKind::NotFound.into())).unwrap();
-                }
-            }
-        });
+    // Rewrites a record left in YAML by earlier versions in the current format. Content kept
+    // in the record is moved to chunks first, a migration cut short starts over on next startup
+    fn migrate_record(&self, ino: Ino, record: &[u8]) -> StoreResult<FileData> {
+        let (file_data, content) = decode_yaml_record(record)?;
+        let mut ops = content_ops(ino, &content);
 
-        let res = rx.recv();
-        match res {
-            Ok(res) => res,
-            Err(_) => Err(ErrorKind::BrokenPipe.into()),
+        while !ops.is_empty() {
+            let batch = ops.drain(..ops.len().min(MAX_TXN_CHUNKS)).collect();
+            self.commit_ops(batch)?;
         }
+
+        self.put_file_data(ino, &file_data)?;
+        Ok(file_data)
     }
 
-    fn write_data(&mut self, ino: Ino, data: &[u8], offset: i64) -> io::Result<u32> {
-        let data = data.to_vec();
-        let len = data.len();
-        let (tx, rx) = mpsc::channel::<io::Result<()>>();
+    fn get_format_version(&self) -> StoreResult<u8> {
         let mut client = self.client.clone();
 
-        tokio::spawn(async move {
-            let res = client.get(ino.to_string(), None).await;
-
-            match res {
-                Ok(res) => {
-                    let kv = res.kvs().iter().nth(0).unwrap();
-                    let str_file_data = kv.value_str().unwrap();
-                    let mut file_data: FileData = serde_yaml::from_str(str_file_data).unwrap();
-                    if offset > 0 {
-                        file_data.data.extend(data);
-                    } else {
-                        file_data.data = data.to_vec();
-                    }
-                    file_data.attr.size = file_data.data.len() as u64;
-
-                    let payload = serde_yaml::to_string(&file_data).unwrap();
-                    let res = client.put(ino.to_string(), payload, None).await;
+        block_on(async move {
+            let res = client.get(FORMAT_KEY, None).await.map_err(backend_error)?;
 
-                    match res {
-                        Ok(_) => tx.send(Ok(())).unwrap(),
-                        Err(_) => tx.send(Err(ErrorKind::BrokenPipe.into())).unwrap(),
-                    };
-                }
-                Err(_) => {
-                    tx.send(Err(ErrorKind::NotFound.into())).unwrap();
-                }
+            match res.kvs().first() {
+                Some(kv) => kv
+                    .value_str()
+                    .ok()
+                    .and_then(|value| value.parse::<u8>().ok())
+                    .ok_or(StoreError::Backend("invalid format version".to_owned())),
+                None => Ok(0),
             }
-        });
+        })
+    }
 
-        let res = rx.recv();
-        match res {
-            Ok(_) => Ok(len as u32),
-            Err(_) => Err(ErrorKind::BrokenPipe.into()),
-        }
+    // Applies all the operations in a single etcd transaction
+    fn commit_ops(&self, ops: Vec<TxnOp>) -> StoreResult<()> {
+        self.commit_ops_if(vec![], ops).map(|_| ())
     }
 
-    fn open_file(&self, ino: Ino) -> Option<Ino> {
-        let (tx, rx) = mpsc::channel();
+    // Same as commit_ops, but only if all the comparisons hold. Tells the revision the
+    // operations were committed at when they did
+    fn commit_ops_if(&self, compares: Vec<Compare>, ops: Vec<TxnOp>) -> StoreResult<Option<i64>> {
         let mut client = self.client.clone();
 
-        tokio::spawn(async move {
-            let res = client.get(ino.to_string(), None).await;
-
-            match res {
-                Ok(res) => {
-                    let bytes_ino = res.kvs().last().unwrap().key();
-                    let ino = String::from_utf8(bytes_ino.to_vec())
-                        .unwrap()
-                        .parse::<Ino>()
-                        .unwrap();
-                    tx.send(Some(ino)).unwrap();
-                }
-                Err(_) => {
-                    tx.send(None).unwrap();
-                }
-            }
-        });
+        block_on(async move {
+            let txn = Txn::new().when(compares).and_then(ops);
+            let res = client.txn(txn).await.map_err(backend_error)?;
 
-        rx.recv().unwrap()
+            let revision = res.header().map_or(0, |header| header.revision());
+            Ok(res.succeeded().then_some(revision))
+        })
     }
+}
 
-    fn create_dir(
-        &mut self,
-        name: String,
-        parent: Ino,
-        uid: u32,
-        gid: u32,
-    ) -> io::Result<FileAttr> {
-        self.ino_count += 1;
-        let new_ino = self.ino_count;
-
-        let file_attr = create_attr(new_ino, uid, gid, FileType::Directory);
-        let file_data = FileData {
-            name: name.clone(),
-            attr: file_attr.clone(),
-            parent: Some(parent),
-            data: vec![],
-        };
+// Reads every inode key in etcd, a page at a time so that large stores don't go over the size
+// limit of a single response. All pages are read at the revision of the first.
+// File content isn't needed for the index, the chunk keys are left out
+async fn fetch_records(client: &mut Client) -> StoreResult<(Vec<Record>, i64)> {
+    let mut records = vec![];
+    let mut from = vec![0];
+    let mut revision = 0;
+
+    loop {
+        let mut options = GetOptions::new()
+            .with_range(CHUNK_KEYS)
+            .with_limit(SCAN_BATCH);
+        if revision > 0 {
+            options = options.with_revision(revision);
+        }
+        let res = client
+            .get(from.clone(), Some(options))
+            .await
+            .map_err(backend_error)?;
+        if revision == 0 {
+            revision = res.header().map_or(0, |header| header.revision());
+        }
 
-        let (tx, rx) = mpsc::channel();
-        let mut client = self.client.clone();
+        for kv in res.kvs() {
+            // Other keys in the cluster don't belong to the store
+            let Some(ino) = kv.key_str().ok().and_then(|key| key.parse::<Ino>().ok()) else {
+                continue;
+            };
 
-        tokio::spawn(async move {
-            let payload = serde_yaml::to_string(&file_data).unwrap();
-            let res = client.put(new_ino.to_string(), payload, None).await;
+            records.push((ino, kv.value().to_vec(), kv.mod_revision()));
+        }
 
-            match res {
-                Ok(_) => tx.send(Ok(())).unwrap(),
-                Err(_) => tx.send(Err(())).unwrap(),
+        match res.kvs().last() {
+            Some(kv) if res.more() => {
+                from = kv.key().to_vec();
+                from.push(0);
             }
-        });
+            _ => break,
+        }
+    }
 
-        let res = rx.recv();
-        match res {
-            Ok(_) => {
-                self.dirs.insert(new_ino, vec![]);
-                self.dirs
-                    .iter_mut()
-                    .find(|(&i, _)| i == parent)
-                    .unwrap()
-                    .1
-                    .push(new_ino);
-                self.ino_to_name.insert(new_ino, name);
-                return Ok(file_attr);
-            }
-            Err(_) => Err(ErrorKind::BrokenPipe.into()),
+    Ok((records, revision))
+}
+
+// Renews the lease until it expires, or fails with what stopped the renewals
+async fn keep_lease_alive(client: &mut Client, lease: i64) -> StoreResult<()> {
+    let (mut keeper, mut responses) = client
+        .lease_keep_alive(lease)
+        .await
+        .map_err(backend_error)?;
+
+    loop {
+        keeper.keep_alive().await.map_err(backend_error)?;
+        match responses.message().await.map_err(backend_error)? {
+            Some(res) if res.ttl() > 0 => {}
+            // The lease is gone along with the open keys
+            Some(_) => return Ok(()),
+            None => return Err(StoreError::Backend("keep-alive stream closed".to_owned())),
         }
+
+        tokio::time::sleep(LEASE_RENEWAL).await;
     }
+}
 
-    fn get_dir_entries(&self, ino: Ino) -> Vec<(u64, FileType, String)> {
-        let mut entries = vec![
-            (ino, FileType::Directory, ".".to_owned()),
-            (ino, FileType::Directory, "..".to_owned()),
-        ];
-        let child_inos = self.dirs.get(&ino).unwrap().clone();
+// Grants a new lease in place of an expired one and puts the keys of the open files back
+// under it. Files released while the keys were put back have theirs dropped again
+async fn replace_lease(
+    client: &mut Client,
+    open_handles: &Mutex<HashMap<Ino, u32>>,
+    lease: &AtomicI64,
+) -> StoreResult<()> {
+    let res = client.lease_grant(LEASE_TTL, None).await;
+    let new_lease = res.map_err(backend_error)?.id();
+
+    let inos: Vec<Ino> = open_handles.lock().unwrap().keys().copied().collect();
+    for batch in inos.chunks(MAX_TXN_OPS) {
+        let ops: Vec<_> = batch
+            .iter()
+            .map(|&ino| open_put_op(ino, new_lease))
+            .collect();
+        let txn = Txn::new().and_then(ops);
+        client.txn(txn).await.map_err(backend_error)?;
+    }
+    lease.store(new_lease, Ordering::SeqCst);
+
+    let released: Vec<_> = {
+        let open_handles = open_handles.lock().unwrap();
+        inos.into_iter()
+            .filter(|ino| !open_handles.contains_key(ino))
+            .collect()
+    };
+    for batch in released.chunks(MAX_TXN_OPS) {
+        let ops: Vec<_> = batch
+            .iter()
+            .map(|&ino| TxnOp::delete(open_key(ino, new_lease), None))
+            .collect();
+        let txn = Txn::new().and_then(ops);
+        client.txn(txn).await.map_err(backend_error)?;
+    }
 
-        let (tx, rx) = mpsc::channel();
-        let mut client = self.client.clone();
+    Ok(())
+}
 
-        tokio::spawn(async move {
-            let mut result = vec![];
-            for ino in child_inos.iter() {
-                let res = client.get(ino.to_string(), None).await;
-
-                match res {
-                    Ok(res) => res.kvs().iter().for_each(|kv| {
-                        let data = kv.value_str().unwrap();
-                        let file_data = serde_yaml::from_str::<FileData>(data).unwrap();
-
-                        let name = file_data.name;
-                        let ino = file_data.attr.ino;
-                        let file_type = file_data.attr.kind;
-
-                        result.push((ino, file_type, name));
-                    }),
-                    Err(_) => {}
-                }
-            }
+// Indexes the changes committed from `revision` on as they come, until the watch breaks.
+// Content is always written along with the file's key, the chunk keys don't need to be
+// followed. Tells why the watch stopped
+async fn follow_changes(
+    client: &mut Client,
+    index: &Mutex<Index>,
+    invalidations: &mpsc::Sender<Invalidation>,
+    revision: i64,
+) -> String {
+    let options = WatchOptions::new()
+        .with_range(CHUNK_KEYS)
+        .with_start_revision(revision);
+    let (_watcher, mut stream) = match client.watch(vec![0], Some(options)).await {
+        Ok(watch) => watch,
+        Err(e) => return e.to_string(),
+    };
+
+    loop {
+        let res = match stream.message().await {
+            Ok(Some(res)) => res,
+            Ok(None) => return "watch closed".to_owned(),
+            Err(e) => return e.to_string(),
+        };
+        if res.compact_revision() > 0 {
+            return format!("compacted up to revision [{}]", res.compact_revision());
+        }
+        if res.canceled() {
+            return "watch canceled".to_owned();
+        }
 
-            tx.send(result).unwrap();
-        });
+        for event in res.events() {
+            let Some(kv) = event.kv() else {
+                continue;
+            };
+            // Other keys in the cluster don't belong to the store
+            let Some(ino) = kv.key_str().ok().and_then(|key| key.parse::<Ino>().ok()) else {
+                continue;
+            };
+
+            let file_data = match event.event_type() {
+                EventType::Put => match decode_file_data(kv.value()) {
+                    Ok(file_data) => Some(file_data),
+                    Err(e) => {
+                        println!("Skipping change to inode [{}]: [{}]", ino, e);
+                        continue;
+                    }
+                },
+                EventType::Delete => None,
+            };
+
+            let changed = index
+                .lock()
+                .unwrap()
+                .apply(ino, file_data.as_ref(), kv.mod_revision());
+            report_changes(invalidations, ino, changed);
+        }
+    }
+}
 
-        let res = rx.recv();
-        match res {
-            Ok(res) => {
-                entries.extend(res);
-                return entries;
+// Indexes what changed in etcd while the watch was down from a new scan, returning the
+// revision the index is now up to date with
+async fn catch_up(
+    client: &mut Client,
+    index: &Mutex<Index>,
+    invalidations: &mpsc::Sender<Invalidation>,
+) -> StoreResult<i64> {
+    let (records, revision) = fetch_records(client).await?;
+    let mut index = index.lock().unwrap();
+    let mut scanned = HashSet::new();
+
+    for (ino, record, mod_revision) in records {
+        scanned.insert(ino);
+
+        match decode_file_data(&record) {
+            Ok(file_data) => {
+                let changed = index.apply(ino, Some(&file_data), mod_revision);
+                report_changes(invalidations, ino, changed);
             }
-            Err(_) => vec![],
+            Err(e) => println!("Skipping change to inode [{}]: [{}]", ino, e),
         }
     }
 
-    fn get_file_attr(&self, ino: Ino) -> Option<FileAttr> {
-        let (tx, rx) = mpsc::channel();
-        let mut client = self.client.clone();
+    // Files known to the index that were deleted in the meantime. Those this mount created
+    // after the scan are indexed at a later revision and stay
+    let deleted: HashSet<Ino> = index
+        .links
+        .keys()
+        .chain(index.dirs.keys())
+        .chain(index.charges.keys())
+        .filter(|ino| !scanned.contains(ino))
+        .copied()
+        .collect();
+    for ino in deleted {
+        let changed = index.apply(ino, None, revision);
+        report_changes(invalidations, ino, changed);
+    }
 
-        tokio::spawn(async move {
-            let res = client.get(ino.to_string(), None).await;
+    Ok(revision)
+}
 
-            match res {
-                Ok(res) => {
-                    res.kvs().iter().for_each(|kv| {
-                        let data = kv.value_str().unwrap();
-                        let file_data = serde_yaml::from_str::<FileData>(data).unwrap();
-                        let attr = file_data.attr.clone();
+// Tells the kernel which of its caches a change to `ino` made stale, if it wasn't indexed yet
+fn report_changes(
+    invalidations: &mpsc::Sender<Invalidation>,
+    ino: Ino,
+    changed: Option<Vec<(Ino, OsString)>>,
+) {
+    let Some(changed) = changed else {
+        return;
+    };
+
+    let _ = invalidations.send(Invalidation::Inode(ino));
+    for (parent, name) in changed {
+        let _ = invalidations.send(Invalidation::Entry(parent, name));
+        let _ = invalidations.send(Invalidation::Inode(parent));
+    }
+}
 
-                        tx.send(Some(attr)).unwrap();
-                    });
-                }
-                Err(_) => {
-                    tx.send(None).unwrap();
-                }
-            }
-        });
+// Chunk indexes are zero-padded so that the chunks of a file sort in order
+fn chunk_key(ino: Ino, index: u64) -> String {
+    format!("{}{}/{:016x}", CHUNK_KEYS, ino, index)
+}
 
-        let res = rx.recv();
-        match res {
-            Ok(res) => res,
-            Err(_) => None,
-        }
+fn chunk_index(key: &str) -> Option<u64> {
+    u64::from_str_radix(key.rsplit('/').next()?, 16).ok()
+}
+
+// Indexes of the chunks the bytes in `range` fall in
+fn chunk_indexes(range: &Range<u64>) -> Range<u64> {
+    range.start / CHUNK_SIZE..range.end.div_ceil(CHUNK_SIZE)
+}
+
+// Key range of the chunks of `ino` from `index` on, '0' being the character right after '/'
+fn chunks_range(ino: Ino, index: u64) -> Range<String> {
+    chunk_key(ino, index)..format!("{}{}0", CHUNK_KEYS, ino)
+}
+
+// Deletes the chunks of `ino` from `index` on
+fn delete_chunks_op(ino: Ino, index: u64) -> TxnOp {
+    let range = chunks_range(ino, index);
+
+    TxnOp::delete(
+        range.start,
+        Some(DeleteOptions::new().with_range(range.end)),
+    )
+}
+
+// Deleting a file takes its content along
+fn delete_ops(ino: Ino) -> Vec<TxnOp> {
+    vec![
+        TxnOp::delete(ino.to_string(), None),
+        delete_chunks_op(ino, 0),
+    ]
+}
+
+// Operations writing `content` from the start of the file
+fn content_ops(ino: Ino, content: &[u8]) -> Vec<TxnOp> {
+    content
+        .chunks(CHUNK_SIZE as usize)
+        .enumerate()
+        .map(|(index, chunk)| TxnOp::put(chunk_key(ino, index as u64), chunk, None))
+        .collect()
+}
+
+fn decode_chunks(kvs: &[KeyValue]) -> StoreResult<HashMap<u64, Vec<u8>>> {
+    kvs.iter()
+        .map(|kv| {
+            let index = kv.key_str().ok().and_then(chunk_index);
+            let index = index.ok_or(StoreError::Backend("invalid chunk key".to_owned()));
+            Ok((index?, kv.value().to_vec()))
+        })
+        .collect()
+}
+
+// Content of the file in `range` out of the chunks it spans, missing chunks being holes
+fn assemble_content(chunks: HashMap<u64, Vec<u8>>, range: Range<u64>) -> Vec<u8> {
+    let mut content = vec![0; (range.end - range.start) as usize];
+    for (index, chunk) in chunks {
+        copy_from_chunk(&mut content, range.start, &chunk, index * CHUNK_SIZE);
     }
 
-    fn delete_dir(&mut self, name: String) -> io::Result<()> {
-        let file_ino = self.ino_to_name.iter().find(|(_, n)| **n == name);
+    content
+}
 
-        if let Some((&dir_ino, _)) = file_ino {
-            let child_inos = self.dirs.get(&dir_ino).unwrap().clone();
+// Copies the part of `chunk` that falls within `content`, both given with their offset in
+// the file. Chunks can be shorter than CHUNK_SIZE, the rest of the content stays zeroed
+fn copy_from_chunk(content: &mut [u8], content_start: u64, chunk: &[u8], chunk_start: u64) {
+    let start = content_start.max(chunk_start);
+    let end = (content_start + content.len() as u64).min(chunk_start + chunk.len() as u64);
 
-            let (tx, rx) = mpsc::channel::<io::Result<()>>();
-            let mut client = self.client.clone();
+    if start < end {
+        content[(start - content_start) as usize..(end - content_start) as usize]
+            .copy_from_slice(&chunk[(start - chunk_start) as usize..(end - chunk_start) as usize]);
+    }
+}
 
-            tokio::spawn(async move {
-                let inos_to_delete: Vec<String> = child_inos
-                    .iter()
-                    .chain(Some(&dir_ino))
-                    .map(|i| i.to_string())
-                    .collect();
+// Chunks once `data` is written at `start` over the current `chunks` of the range it spans,
+// missing chunks being holes
+fn write_chunks(mut chunks: HashMap<u64, Vec<u8>>, data: &[u8], start: u64) -> Vec<(u64, Vec<u8>)> {
+    chunk_indexes(&(start..start + data.len() as u64))
+        .map(|index| {
+            let mut chunk = chunks.remove(&index).unwrap_or_default();
+            copy_to_chunk(&mut chunk, index * CHUNK_SIZE, data, start);
+            (index, chunk)
+        })
+        .collect()
+}
 
-                for ino in inos_to_delete.iter() {
-                    let _ = client.delete(ino.to_owned(), None).await;
-                }
+// Truncating to `size` drops the chunks from the first index returned on, and cuts the chunk
+// the file ends in to the length returned with it, unless the file ends on a chunk boundary
+fn truncation(size: u64) -> (u64, Option<(u64, usize)>) {
+    let cut = (!size.is_multiple_of(CHUNK_SIZE))
+        .then_some((size / CHUNK_SIZE, (size % CHUNK_SIZE) as usize));
 
-                tx.send(Ok(())).unwrap();
-            });
+    (size.div_ceil(CHUNK_SIZE), cut)
+}
 
-            let res = rx.recv();
-            match res {
-                Ok(_) => {
-                    self.ino_to_name.remove(&dir_ino);
-                    self.dirs.iter_mut().for_each(|(_, v)| {
-                        let index_to_remove = v.iter().position(|&i| i == dir_ino);
-                        if let Some(index_to_remove) = index_to_remove {
-                            v.remove(index_to_remove);
-                        }
-                    });
-                    return Ok(());
-                }
-                Err(_) => Err(ErrorKind::BrokenPipe.into()),
-            }
-        } else {
-            return Ok(());
-        }
+// Copies the part of `data` that falls within the chunk, growing it with zeros as needed
+fn copy_to_chunk(chunk: &mut Vec<u8>, chunk_start: u64, data: &[u8], data_start: u64) {
+    let start = data_start.max(chunk_start);
+    let end = (data_start + data.len() as u64).min(chunk_start + CHUNK_SIZE);
+
+    if chunk.len() < (end - chunk_start) as usize {
+        chunk.resize((end - chunk_start) as usize, 0);
     }
+    chunk[(start - chunk_start) as usize..(end - chunk_start) as usize]
+        .copy_from_slice(&data[(start - data_start) as usize..(end - data_start) as usize]);
+}
 
-    fn set_file_attr(
-        &mut self,
-        ino: Ino,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-    ) -> Option<FileAttr> {
-        let (tx, rx) = mpsc::channel();
-        let mut client = self.client.clone();
+// Entry keys of a directory all start with its prefix, names are kept as raw bytes
+fn entries_prefix(parent: Ino) -> Vec<u8> {
+    format!("{}{}/", ENTRY_KEYS, parent).into_bytes()
+}
 
-        tokio::spawn(async move {
-            let res = client.get(ino.to_string(), None).await;
+fn entry_key(parent: Ino, name: &OsStr) -> Vec<u8> {
+    let mut key = entries_prefix(parent);
+    key.extend_from_slice(name.as_bytes());
+    key
+}
 
-            match res {
-                Ok(res) => {
-                    for kv in res.kvs().iter() {
-                        let value = kv.value_str().unwrap();
-                        let mut file_data: FileData = serde_yaml::from_str(value).unwrap();
+fn open_prefix(ino: Ino) -> Vec<u8> {
+    format!("{}{}/", OPEN_KEYS, ino).into_bytes()
+}
 
-                        if let Some(uid) = uid {
-                            file_data.attr.uid = uid;
-                        }
+fn open_key(ino: Ino, lease: i64) -> Vec<u8> {
+    let mut key = open_prefix(ino);
+    key.extend_from_slice(format!("{:x}", lease).as_bytes());
+    key
+}
 
-                        if let Some(gid) = gid {
-                            file_data.attr.gid = gid;
-                        }
+fn open_put_op(ino: Ino, lease: i64) -> TxnOp {
+    let options = PutOptions::new().with_lease(lease);
+    TxnOp::put(open_key(ino, lease), vec![], Some(options))
+}
 
-                        if let Some(size) = size {
-                            file_data.attr.size = size;
-                        }
+fn put_entry_op(parent: Ino, name: &OsStr, ino: Ino) -> TxnOp {
+    TxnOp::put(entry_key(parent, name), ino.to_string(), None)
+}
 
-                        let payload = serde_yaml::to_string(&file_data).unwrap();
-                        let res = client.put(ino.to_string(), payload, None).await;
+fn delete_entry_op(parent: Ino, name: &OsStr) -> TxnOp {
+    TxnOp::delete(entry_key(parent, name), None)
+}
 
-                        match res {
-                            Ok(_) => {
-                                let attr = file_data.attr.clone();
-                                tx.send(Some(attr)).unwrap();
-                            }
-                            Err(_) => {
-                                tx.send(None).unwrap();
-                            }
-                        };
-                    }
-                }
-                Err(_) => {
-                    tx.send(None).unwrap();
-                }
-            }
-        });
+fn put_op(ino: Ino, file_data: &FileData) -> StoreResult<TxnOp> {
+    let mut record = vec![RECORD_FORMAT];
+    bincode::DefaultOptions::new()
+        .serialize_into(&mut record, file_data)
+        .map_err(|e| StoreError::Backend(e.to_string()))?;
 
-        let res = rx.recv();
-        match res {
-            Ok(res) => res,
-            Err(_) => None,
-        }
+    Ok(TxnOp::put(ino.to_string(), record, None))
+}
+
+// Reads records in the current format as well as YAML ones, their content is left out
+fn decode_file_data(record: &[u8]) -> StoreResult<FileData> {
+    match record.first() {
+        Some(&RECORD_FORMAT) => bincode::DefaultOptions::new()
+            .deserialize(&record[1..])
+            .map_err(|e| StoreError::Backend(e.to_string())),
+        _ if is_yaml_record(record) => Ok(decode_yaml_record(record)?.0),
+        format => Err(StoreError::Backend(format!(
+            "unsupported record format [{:?}]",
+            format
+        ))),
+    }
+}
+
+fn is_yaml_record(record: &[u8]) -> bool {
+    record.first().is_some_and(|byte| !byte.is_ascii_control())
+}
+
+fn decode_yaml_record(record: &[u8]) -> StoreResult<(FileData, Vec<u8>)> {
+    let yaml_data: YamlFileData =
+        serde_yaml::from_slice(record).map_err(|e| StoreError::Backend(e.to_string()))?;
+
+    Ok(yaml_data.into_parts())
+}
+
+// Runs an etcd request on the runtime and waits for its result, the store being synchronous.
+// A request dropped with the runtime on shutdown fails like any other
+fn block_on<T: Send + 'static>(
+    request: impl Future<Output = StoreResult<T>> + Send + 'static,
+) -> StoreResult<T> {
+    let (tx, rx) = mpsc::channel();
+
+    tokio::spawn(async move {
+        let _ = tx.send(request.await);
+    });
+
+    match rx.recv() {
+        Ok(res) => res,
+        Err(_) => Err(StoreError::Backend("etcd request dropped".to_owned())),
     }
 }
 
-fn create_attr(ino: Ino, uid: u32, gid: u32, kind: FileType) -> FileAttr {
-    let mut perm = 0o644;
-    if kind == FileType::Directory {
-        perm = 0o755;
+// etcd refuses writes once its backend quota is used up
+fn backend_error(e: etcd_client::Error) -> StoreError {
+    match e {
+        etcd_client::Error::GRpcStatus(status)
+            if status.message().contains("database space exceeded") =>
+        {
+            StoreError::NoSpace
+        }
+        e => StoreError::Backend(e.to_string()),
     }
+}
 
+fn create_attr(ino: Ino, uid: u32, gid: u32, kind: FileType, perm: u16) -> FileAttr {
     FileAttr {
         ino,
         kind,
@@ -575,7 +2107,7 @@ fn create_attr(ino: Ino, uid: u32, gid: u32, kind: FileType) -> FileAttr {
         mtime: SystemTime::now(),
         ctime: SystemTime::now(),
         crtime: SystemTime::now(),
-        nlink: 1,
+        nlink: if kind == FileType::Directory { 2 } else { 1 },
         rdev: 0,
         flags: 0,
         blksize: 512,
@@ -596,3 +2128,229 @@ fn get_etcd_endpoint_from_env(default: String) -> String {
         return default;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INO: Ino = 1;
+
+    // Chunks of a single file by index, standing in for its chunk keys in etcd
+    type Chunks = HashMap<u64, Vec<u8>>;
+
+    fn write(chunks: &mut Chunks, data: &[u8], start: u64) {
+        let current = chunk_indexes(&(start..start + data.len() as u64))
+            .filter_map(|index| Some((index, chunks.get(&index)?.clone())))
+            .collect();
+        chunks.extend(write_chunks(current, data, start));
+    }
+
+    // Goes through the key range deleted by truncate_ops
+    fn truncate(chunks: &mut Chunks, size: u64) {
+        let (dropped, cut) = truncation(size);
+        let deleted = chunks_range(INO, dropped);
+        chunks.retain(|&index, _| !deleted.contains(&chunk_key(INO, index)));
+        if let Some((last, len)) = cut {
+            if let Some(chunk) = chunks.get_mut(&last) {
+                chunk.truncate(len);
+            }
+        }
+    }
+
+    // Goes through the chunks fetched by read_content
+    fn read(chunks: &Chunks, range: Range<u64>) -> Vec<u8> {
+        let fetched = chunk_indexes(&range)
+            .filter_map(|index| Some((index, chunks.get(&index)?.clone())))
+            .collect();
+        assemble_content(fetched, range)
+    }
+
+    fn pattern(len: u64, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect()
+    }
+
+    #[test]
+    fn chunk_keys_sort_by_index() {
+        assert_eq!(chunk_key(12, 0x1f), "data/12/000000000000001f");
+        assert!(chunk_key(INO, 9) < chunk_key(INO, 10));
+        assert!(chunk_key(INO, 0xff) < chunk_key(INO, 0x100));
+
+        for index in [0, 1, CHUNK_SIZE, u64::MAX] {
+            assert_eq!(chunk_index(&chunk_key(INO, index)), Some(index));
+        }
+        assert_eq!(chunk_index("data/1/zz"), None);
+    }
+
+    #[test]
+    fn chunks_range_only_covers_the_file() {
+        let all = chunks_range(INO, 0);
+        assert!(all.contains(&chunk_key(INO, 0)));
+        assert!(all.contains(&chunk_key(INO, u64::MAX)));
+
+        // Inodes sharing the decimal prefix sort right before or after the range
+        for other in [10, 11, 100, 19] {
+            assert!(!all.contains(&chunk_key(other, 0)));
+            assert!(!all.contains(&chunk_key(other, u64::MAX)));
+        }
+        assert!(!all.contains(&INO.to_string()));
+
+        let tail = chunks_range(INO, 3);
+        assert!(!tail.contains(&chunk_key(INO, 2)));
+        assert!(tail.contains(&chunk_key(INO, 3)));
+    }
+
+    #[test]
+    fn chunk_indexes_of_ranges() {
+        assert_eq!(chunk_indexes(&(0..0)), 0..0);
+        assert_eq!(chunk_indexes(&(0..1)), 0..1);
+        assert_eq!(chunk_indexes(&(0..CHUNK_SIZE)), 0..1);
+        assert_eq!(chunk_indexes(&(CHUNK_SIZE - 1..CHUNK_SIZE + 1)), 0..2);
+        assert_eq!(chunk_indexes(&(3 * CHUNK_SIZE..3 * CHUNK_SIZE + 5)), 3..4);
+    }
+
+    #[test]
+    fn copy_from_chunk_only_copies_the_overlap() {
+        let chunk = pattern(10, 1);
+
+        let mut content = vec![0; 4];
+        copy_from_chunk(&mut content, 8, &chunk, 0);
+        assert_eq!(content, [chunk[8], chunk[9], 0, 0]);
+
+        let mut content = vec![0; 4];
+        copy_from_chunk(&mut content, 0, &chunk, 2);
+        assert_eq!(content, [0, 0, chunk[0], chunk[1]]);
+
+        let mut content = vec![7; 4];
+        copy_from_chunk(&mut content, 20, &chunk, 0);
+        assert_eq!(content, [7; 4]);
+    }
+
+    #[test]
+    fn copy_to_chunk_grows_with_zeros() {
+        let mut chunk = vec![1, 2];
+        copy_to_chunk(&mut chunk, 0, &[9, 9], 4);
+        assert_eq!(chunk, [1, 2, 0, 0, 9, 9]);
+
+        // Data past the end of the chunk is left to the next one
+        let mut chunk = vec![];
+        let data = pattern(6, 2);
+        copy_to_chunk(&mut chunk, CHUNK_SIZE, &data, 2 * CHUNK_SIZE - 2);
+        assert_eq!(chunk.len() as u64, CHUNK_SIZE);
+        assert_eq!(chunk[CHUNK_SIZE as usize - 2..], data[..2]);
+    }
+
+    #[test]
+    fn write_across_chunk_boundaries() {
+        let mut chunks = Chunks::new();
+        write(&mut chunks, &pattern(100, 0), 0);
+
+        let start = CHUNK_SIZE - 10;
+        let data = pattern(CHUNK_SIZE + 20, 5);
+        write(&mut chunks, &data, start);
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[&0].len() as u64, CHUNK_SIZE);
+        assert_eq!(chunks[&1].len() as u64, CHUNK_SIZE);
+        assert_eq!(chunks[&2].len(), 10);
+        assert_eq!(read(&chunks, 0..100), pattern(100, 0));
+        assert_eq!(read(&chunks, start..start + data.len() as u64), data);
+    }
+
+    #[test]
+    fn sparse_writes_leave_holes() {
+        let mut chunks = Chunks::new();
+        write(&mut chunks, &[1, 2, 3], 3 * CHUNK_SIZE + 1);
+
+        assert_eq!(chunks.keys().collect::<Vec<_>>(), [&3]);
+        assert_eq!(chunks[&3], [0, 1, 2, 3]);
+        assert_eq!(
+            read(&chunks, 0..3 * CHUNK_SIZE),
+            vec![0; 3 * CHUNK_SIZE as usize]
+        );
+        assert_eq!(
+            read(&chunks, 3 * CHUNK_SIZE..3 * CHUNK_SIZE + 4),
+            [0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn truncation_boundaries() {
+        assert_eq!(truncation(0), (0, None));
+        assert_eq!(truncation(1), (1, Some((0, 1))));
+        assert_eq!(truncation(CHUNK_SIZE), (1, None));
+        assert_eq!(truncation(2 * CHUNK_SIZE + 7), (3, Some((2, 7))));
+    }
+
+    #[test]
+    fn truncate_in_the_middle_of_a_chunk() {
+        let size = 3 * CHUNK_SIZE;
+        let content = pattern(size, 3);
+        let mut chunks = Chunks::new();
+        write(&mut chunks, &content, 0);
+
+        let cut = CHUNK_SIZE + 100;
+        truncate(&mut chunks, cut);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[&1].len(), 100);
+        assert_eq!(read(&chunks, 0..cut), content[..cut as usize]);
+
+        // Growing the file again reads zeros where the content was dropped
+        let mut expected = content[..cut as usize].to_vec();
+        expected.resize(size as usize, 0);
+        assert_eq!(read(&chunks, 0..size), expected);
+
+        truncate(&mut chunks, 0);
+        assert!(chunks.is_empty());
+    }
+
+    // Needs etcd on localhost, as started by start_etcd_docker.sh: cargo test -- --ignored
+    #[test]
+    #[ignore]
+    fn open_after_the_lease_expired() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let (tx, _rx) = mpsc::channel();
+        let mut store = EtcdStore::new(tx).unwrap();
+
+        let name = format!("lease-{}", std::process::id());
+        let kept = store
+            .create_file(format!("{}-kept", name).into(), ROOT_INO, 0, 0, 0o644, 0)
+            .unwrap()
+            .ino;
+        store.open_file(kept, 0).unwrap();
+
+        let expired = store.lease.load(Ordering::SeqCst);
+        let mut client = store.client.clone();
+        runtime.block_on(async move { client.lease_revoke(expired).await.unwrap() });
+
+        // The renewals notice the lease is gone and put the open keys under a new one
+        let deadline = Instant::now() + Duration::from_secs(2 * LEASE_TTL as u64);
+        while store.lease.load(Ordering::SeqCst) == expired {
+            assert!(Instant::now() < deadline, "lease never replaced");
+            thread::sleep(LEASE_POLL);
+        }
+        let lease = store.current_lease().unwrap();
+        assert_ne!(lease, expired);
+        assert_eq!(store.count_keys(open_prefix(kept)).unwrap(), 1);
+
+        let opened = store
+            .create_file(format!("{}-opened", name).into(), ROOT_INO, 0, 0, 0o644, 0)
+            .unwrap()
+            .ino;
+        store.open_file(opened, 0).unwrap();
+        assert_eq!(store.count_keys(open_prefix(opened)).unwrap(), 1);
+
+        for ino in [kept, opened] {
+            store.release_file(ino).unwrap();
+            assert_eq!(store.count_keys(open_prefix(ino)).unwrap(), 0);
+        }
+        store
+            .delete_file(ROOT_INO, format!("{}-kept", name).into())
+            .unwrap();
+        store
+            .delete_file(ROOT_INO, format!("{}-opened", name).into())
+            .unwrap();
+    }
+}
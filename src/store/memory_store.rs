@@ -1,26 +1,51 @@
+use super::acl::{chmod_acl, inherit_acl, ACL_ACCESS};
+use super::quota::Quotas;
+use super::store::check_name;
+use super::store::set_xattr;
+use super::store::AttrChanges;
+use super::store::Capacity;
 use super::store::FileInfo;
+use super::store::Invalidation;
 use super::store::Store;
+use super::store::StoreError;
+use super::store::StoreResult;
+use super::store::StoreStats;
+use super::store::Xattrs;
 use fuser::FileAttr;
 use fuser::FileType;
-use libc::ENOTEMPTY;
-use std::io;
-use std::{collections::HashMap, time::SystemTime};
+use libc::{O_TRUNC, RENAME_EXCHANGE, RENAME_NOREPLACE};
+use std::ffi::{OsStr, OsString};
+use std::{collections::HashMap, sync::mpsc, time::SystemTime};
 
 type Ino = <MemoryStore as Store>::Ino;
+const DEFAULT_CAPACITY: Capacity = Capacity {
+    bytes: 1 << 30,
+    inodes: 1 << 20,
+};
 
 pub struct MemoryStore {
     ino_counter: Ino,
     files: HashMap<Ino, FileInfo>,
     files_data: HashMap<Ino, Vec<u8>>,
+    xattrs: HashMap<Ino, Xattrs>,
+    // Directory entries, a file shows up once for each of its hard links
+    dirs: HashMap<Ino, Vec<(OsString, Ino)>>,
+    open_handles: HashMap<Ino, u32>,
+    quotas: Quotas,
 }
 
 impl Store for MemoryStore {
     type Ino = u64;
-    fn new() -> io::Result<Self> {
+    // Nothing else changes the files of an in-memory store
+    fn new(_invalidations: mpsc::Sender<Invalidation>) -> StoreResult<Self> {
         let mut store = MemoryStore {
             ino_counter: 1,
             files: HashMap::new(),
             files_data: HashMap::new(),
+            xattrs: HashMap::new(),
+            dirs: HashMap::new(),
+            open_handles: HashMap::new(),
+            quotas: Quotas::from_env(DEFAULT_CAPACITY),
         };
 
         let root_dir_attr = FileAttr {
@@ -44,56 +69,125 @@ impl Store for MemoryStore {
         store.files.insert(
             1,
             FileInfo {
-                name: ".".to_owned(),
+                name: "/".into(),
                 attr: root_dir_attr,
-                parent: Some(1),
+                parent: None,
             },
         );
+        store.dirs.insert(1, vec![]);
+        store.quotas.charge(0, 0, 0, 1);
 
         return Ok(store);
     }
 
-    fn delete_file(&mut self, name: String) -> io::Result<()> {
-        let file_to_remove = self.files.iter().find(|(_, info)| info.name == name);
+    fn delete_file(&mut self, parent: Ino, name: OsString) -> StoreResult<()> {
+        let (ino, info) = self.lookup_file(name, parent)?;
 
-        if let Some((&ino, info)) = file_to_remove {
-            if info.attr.kind == FileType::RegularFile {
-                self.files_data.remove(&ino);
-                self.files.remove(&ino);
-            }
+        if info.attr.kind == FileType::Directory {
+            return Err(StoreError::IsADirectory);
         }
 
+        self.detach(parent, &info.name);
+        self.drop_link(ino);
+
         Ok(())
     }
 
-    fn write_data(&mut self, ino: Ino, data: &[u8], offset: i64) -> io::Result<u32> {
-        let is_append = offset > 0;
+    fn write_data(&mut self, ino: Ino, data: &[u8], offset: i64, append: bool) -> StoreResult<u32> {
+        let filedata = self.files_data.get_mut(&ino).ok_or(StoreError::NotFound)?;
+        let fileinfo = self.files.get_mut(&ino).ok_or(StoreError::NotFound)?;
 
-        let filedata = self.files_data.get_mut(&ino).unwrap();
-        if is_append {
-            filedata.extend_from_slice(data);
+        let start = if append {
+            filedata.len()
         } else {
-            filedata.clone_from(&data.to_vec());
+            offset as usize
+        };
+        let end = start + data.len();
+        let growth = end.saturating_sub(filedata.len()) as i64;
+        self.quotas
+            .check(fileinfo.attr.uid, fileinfo.attr.gid, growth, 0)?;
+
+        // Writing past the end of the file leaves a zero-filled hole
+        if end > filedata.len() {
+            filedata.resize(end, 0);
         }
+        filedata[start..end].copy_from_slice(data);
 
-        let fileinfo = self.files.get_mut(&ino).unwrap();
         fileinfo.attr.size = filedata.len() as u64;
+        self.quotas
+            .charge(fileinfo.attr.uid, fileinfo.attr.gid, growth, 0);
 
         Ok(data.len() as u32)
     }
 
-    fn open_file(&self, ino: Ino) -> Option<Ino> {
+    fn open_file(&mut self, ino: Ino, flags: i32) -> StoreResult<Ino> {
+        if flags & O_TRUNC != 0 {
+            let changes = AttrChanges {
+                size: Some(0),
+                ..Default::default()
+            };
+            self.set_file_attr(ino, changes)?;
+        }
+
         let res = self.files.keys().find(|&i| ino == *i);
         match res {
-            Some(_) => Some(ino),
-            None => None,
+            Some(_) => {
+                *self.open_handles.entry(ino).or_insert(0) += 1;
+                Ok(ino)
+            }
+            None => Err(StoreError::NotFound),
+        }
+    }
+
+    fn release_file(&mut self, ino: Ino) -> StoreResult<()> {
+        if let Some(count) = self.open_handles.get_mut(&ino) {
+            *count -= 1;
+            if *count == 0 {
+                self.open_handles.remove(&ino);
+                self.free_if_unused(ino);
+            }
         }
+
+        Ok(())
     }
 
-    fn read_data(&self, ino: Ino, offset: i64, size: u32) -> io::Result<Vec<u8>> {
-        let filedata = self.files_data.get(&ino).unwrap();
+    fn flush_file(&mut self, ino: Ino) -> StoreResult<()> {
+        // Content only lives in memory, there is nothing to persist
+        if !self.files.contains_key(&ino) {
+            return Err(StoreError::NotFound);
+        }
+
+        Ok(())
+    }
 
-        let start = offset as usize;
+    fn link_file(
+        &mut self,
+        ino: Ino,
+        new_parent: Ino,
+        new_name: OsString,
+    ) -> StoreResult<FileAttr> {
+        let kind = self.files.get(&ino).ok_or(StoreError::NotFound)?.attr.kind;
+
+        // Directories can't be hard linked
+        if kind == FileType::Directory {
+            return Err(StoreError::NotPermitted);
+        }
+
+        self.check_new_entry(new_parent, &new_name)?;
+
+        self.attach(new_parent, new_name, ino);
+
+        let fileinfo = self.files.get_mut(&ino).unwrap();
+        fileinfo.attr.nlink += 1;
+        fileinfo.attr.ctime = SystemTime::now();
+
+        Ok(fileinfo.attr)
+    }
+
+    fn read_data(&self, ino: Ino, offset: i64, size: u32) -> StoreResult<Vec<u8>> {
+        let filedata = self.files_data.get(&ino).ok_or(StoreError::NotFound)?;
+
+        let start = (offset as usize).min(filedata.len());
         let end = if start + size as usize > filedata.len() {
             filedata.len()
         } else {
@@ -105,139 +199,505 @@ impl Store for MemoryStore {
 
     fn create_file(
         &mut self,
-        name: String,
+        name: OsString,
         parent: Ino,
         uid: u32,
         gid: u32,
-    ) -> io::Result<FileAttr> {
+        mode: u32,
+        umask: u32,
+    ) -> StoreResult<FileAttr> {
+        self.check_new_entry(parent, &name)?;
+        self.quotas.check(uid, gid, 0, 1)?;
+
         self.ino_counter += 1;
 
         let ino = self.ino_counter;
-        let attr = create_attr(ino, uid, gid, FileType::RegularFile);
+        let parent_xattrs = self.xattrs.get(&parent).cloned().unwrap_or_default();
+        let (perm, xattrs) = inherit_acl(&parent_xattrs, FileType::RegularFile, mode, umask);
+        let attr = create_attr(ino, uid, gid, FileType::RegularFile, perm);
 
         let new_fileinfo = FileInfo {
             attr,
-            name,
+            name: name.clone(),
             parent: Some(parent),
         };
 
         self.files.insert(ino, new_fileinfo);
         self.files_data.insert(ino, vec![]);
+        self.xattrs.insert(ino, xattrs);
+        self.attach(parent, name, ino);
+        self.quotas.charge(uid, gid, 0, 1);
 
         Ok(attr)
     }
 
-    // Dirs
-    fn lookup_file(&self, name: String, parent: Ino) -> Option<(u64, FileInfo)> {
-        let res = self.files.iter().find(|(_, info)| {
-            info.parent.is_some() && info.parent.unwrap() == parent && info.name == name
-        });
+    fn create_symlink(
+        &mut self,
+        name: OsString,
+        parent: Ino,
+        target: &[u8],
+        uid: u32,
+        gid: u32,
+    ) -> StoreResult<FileAttr> {
+        self.check_new_entry(parent, &name)?;
+        self.quotas.check(uid, gid, target.len() as i64, 1)?;
 
-        match res {
-            Some((&ino, info)) => Some((ino, info.clone())),
-            None => None,
+        self.ino_counter += 1;
+
+        let ino = self.ino_counter;
+        let mut attr = create_attr(ino, uid, gid, FileType::Symlink, 0o777);
+        attr.size = target.len() as u64;
+
+        let new_fileinfo = FileInfo {
+            attr,
+            name: name.clone(),
+            parent: Some(parent),
+        };
+
+        // The link target is kept as the symlink's content
+        self.files.insert(ino, new_fileinfo);
+        self.files_data.insert(ino, target.to_vec());
+        self.attach(parent, name, ino);
+        self.quotas.charge(uid, gid, target.len() as i64, 1);
+
+        Ok(attr)
+    }
+
+    fn read_link(&self, ino: Ino) -> StoreResult<Vec<u8>> {
+        let fileinfo = self.files.get(&ino).ok_or(StoreError::NotFound)?;
+
+        if fileinfo.attr.kind != FileType::Symlink {
+            return Err(StoreError::InvalidArgument);
         }
+
+        Ok(self.files_data.get(&ino).cloned().unwrap_or_default())
+    }
+
+    // Dirs
+    fn lookup_file(&self, name: OsString, parent: Ino) -> StoreResult<(u64, FileInfo)> {
+        if !self.dirs.contains_key(&parent) {
+            return Err(StoreError::NotFound);
+        }
+
+        let ino = match name.to_str() {
+            Some(".") => parent,
+            Some("..") => self.parent_dir(parent),
+            _ => self.find_child(parent, &name).ok_or(StoreError::NotFound)?,
+        };
+        let attr = self.files.get(&ino).ok_or(StoreError::NotFound)?.attr;
+
+        // Reporting the entry the inode was reached through, it may have other links
+        Ok((
+            ino,
+            FileInfo {
+                parent: Some(parent),
+                name,
+                attr,
+            },
+        ))
     }
 
     fn create_dir(
         &mut self,
-        name: String,
+        name: OsString,
         parent: Ino,
         uid: u32,
         gid: u32,
-    ) -> io::Result<FileAttr> {
+        mode: u32,
+        umask: u32,
+    ) -> StoreResult<FileAttr> {
+        self.check_new_entry(parent, &name)?;
+        self.quotas.check(uid, gid, 0, 1)?;
+
         self.ino_counter += 1;
         let ino = self.ino_counter;
-        let attr = create_attr(ino, uid, gid, FileType::Directory);
+        let parent_xattrs = self.xattrs.get(&parent).cloned().unwrap_or_default();
+        let (perm, xattrs) = inherit_acl(&parent_xattrs, FileType::Directory, mode, umask);
+        let attr = create_attr(ino, uid, gid, FileType::Directory, perm);
         let new_fileinfo = FileInfo {
             attr,
-            name,
+            name: name.clone(),
             parent: Some(parent),
         };
 
         self.files.insert(ino, new_fileinfo);
+        self.dirs.insert(ino, vec![]);
+        self.xattrs.insert(ino, xattrs);
+        self.attach(parent, name, ino);
+        self.quotas.charge(uid, gid, 0, 1);
         Ok(attr)
     }
 
-    fn delete_dir(&mut self, name: String) -> io::Result<()> {
-        let dir_to_remove = self.files.iter().find(|(_, info)| info.name == name);
+    fn delete_dir(&mut self, parent: Ino, name: OsString) -> StoreResult<()> {
+        let (dir_ino, info) = self.lookup_file(name, parent)?;
+
+        if info.attr.kind != FileType::Directory {
+            return Err(StoreError::NotADirectory);
+        }
 
-        if let Some((&dir_ino, info)) = dir_to_remove {
-            if info.attr.kind == FileType::Directory {
-                let not_empty = self
-                    .files
-                    .iter()
-                    .filter(|(_, info)| info.parent.is_some() && info.parent.unwrap() == dir_ino)
-                    .map(|(ino, _)| *ino)
-                    .count()
-                    > 0;
+        if self.has_children(dir_ino) {
+            return Err(StoreError::NotEmpty);
+        }
 
-                if not_empty {
-                    return Err(io::Error::from_raw_os_error(ENOTEMPTY));
-                }
+        self.detach(parent, &info.name);
+        self.drop_link(dir_ino);
 
-                self.files_data.remove(&dir_ino);
-                self.files.remove(&dir_ino);
+        Ok(())
+    }
+
+    fn rename_entry(
+        &mut self,
+        parent: Ino,
+        name: OsString,
+        new_parent: Ino,
+        new_name: OsString,
+        flags: u32,
+    ) -> StoreResult<()> {
+        let noreplace = flags & RENAME_NOREPLACE != 0;
+        let exchange = flags & RENAME_EXCHANGE != 0;
+        if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0 || (noreplace && exchange) {
+            return Err(StoreError::InvalidArgument);
+        }
+        check_name(&new_name)?;
+
+        let (ino, info) = self.lookup_file(name.clone(), parent)?;
+
+        // A directory can't be moved inside its own subtree
+        if info.attr.kind == FileType::Directory && self.is_ancestor(ino, new_parent) {
+            return Err(StoreError::InvalidArgument);
+        }
+
+        match self.lookup_file(new_name.clone(), new_parent).ok() {
+            // Even a link to the same file is there already
+            Some(_) if noreplace => return Err(StoreError::AlreadyExists),
+            Some((target_ino, _)) if target_ino == ino => return Ok(()),
+            Some((target_ino, target_info)) => {
+                if exchange {
+                    if target_info.attr.kind == FileType::Directory
+                        && self.is_ancestor(target_ino, parent)
+                    {
+                        return Err(StoreError::InvalidArgument);
+                    }
+
+                    self.detach(parent, &name);
+                    self.detach(new_parent, &new_name);
+                    self.attach(parent, name.clone(), target_ino);
+
+                    let target_fileinfo = self.files.get_mut(&target_ino).unwrap();
+                    target_fileinfo.parent = Some(parent);
+                    target_fileinfo.name = name.clone();
+                    target_fileinfo.attr.ctime = SystemTime::now();
+                } else {
+                    match (info.attr.kind, target_info.attr.kind) {
+                        (FileType::Directory, FileType::Directory)
+                            if self.has_children(target_ino) =>
+                        {
+                            return Err(StoreError::NotEmpty);
+                        }
+                        (FileType::Directory, kind) if kind != FileType::Directory => {
+                            return Err(StoreError::NotADirectory);
+                        }
+                        (kind, FileType::Directory) if kind != FileType::Directory => {
+                            return Err(StoreError::IsADirectory);
+                        }
+                        _ => {}
+                    }
+
+                    self.detach(new_parent, &new_name);
+                    self.drop_link(target_ino);
+                }
             }
+            None if exchange => return Err(StoreError::NotFound),
+            None => {}
         }
 
+        // The exchange case already detached the source entry
+        if !exchange {
+            self.detach(parent, &name);
+        }
+        self.attach(new_parent, new_name.clone(), ino);
+
+        let fileinfo = self.files.get_mut(&ino).unwrap();
+        fileinfo.parent = Some(new_parent);
+        fileinfo.name = new_name;
+        fileinfo.attr.ctime = SystemTime::now();
+
         Ok(())
     }
 
-    fn get_dir_entries(&self, ino: Ino) -> Vec<(u64, FileType, String)> {
-        let mut entries = vec![(ino, FileType::Directory, "..".to_string())];
-        if ino != 1 {
-            entries.push((ino, FileType::Directory, ".".to_string()));
+    fn get_dir_entries(&self, ino: Ino) -> StoreResult<Vec<(u64, FileType, OsString)>> {
+        let children = self.dirs.get(&ino).ok_or(StoreError::NotADirectory)?;
+
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".into()),
+            (self.parent_dir(ino), FileType::Directory, "..".into()),
+        ];
+
+        for (name, ino_child) in children {
+            let kind = self
+                .files
+                .get(ino_child)
+                .ok_or(StoreError::NotFound)?
+                .attr
+                .kind;
+            entries.push((*ino_child, kind, name.clone()));
         }
 
-        self.files.iter().for_each(|(ino_child, info)| {
-            if info.parent.is_some() && info.parent.unwrap() == ino {
-                entries.push((*ino_child, info.attr.kind, info.name.to_string()));
-            }
-        });
+        Ok(entries)
+    }
+
+    fn get_dir_entries_plus(
+        &self,
+        ino: Ino,
+        offset: usize,
+        count: usize,
+    ) -> StoreResult<Vec<(OsString, FileAttr)>> {
+        let children = self.dirs.get(&ino).ok_or(StoreError::NotADirectory)?;
+
+        // The dot entries come first, the parent is only looked up when they are in the batch
+        let dots = 2;
+        let mut entries: Vec<(OsString, Ino)> = vec![];
+        if offset < dots {
+            let dot_entries = [(".".into(), ino), ("..".into(), self.parent_dir(ino))];
+            entries.extend(dot_entries.into_iter().skip(offset));
+        }
+        entries.extend(
+            children
+                .iter()
+                .skip(offset.saturating_sub(dots))
+                .take(count)
+                .cloned(),
+        );
+        entries.truncate(count);
+
+        entries
+            .into_iter()
+            .map(|(name, child_ino)| {
+                let fileinfo = self.files.get(&child_ino).ok_or(StoreError::NotFound)?;
+                Ok((name, fileinfo.attr))
+            })
+            .collect()
+    }
+
+    // Xattrs
+    fn get_xattr(&self, ino: Ino, name: &str) -> StoreResult<Vec<u8>> {
+        if !self.files.contains_key(&ino) {
+            return Err(StoreError::NotFound);
+        }
+
+        self.xattrs
+            .get(&ino)
+            .and_then(|xattrs| xattrs.get(name))
+            .cloned()
+            .ok_or(StoreError::NoAttribute)
+    }
+
+    fn set_xattr(&mut self, ino: Ino, name: String, value: &[u8], flags: i32) -> StoreResult<()> {
+        let fileinfo = self.files.get_mut(&ino).ok_or(StoreError::NotFound)?;
+
+        let xattrs = self.xattrs.entry(ino).or_default();
+        set_xattr(xattrs, &mut fileinfo.attr, name, value, flags)?;
+        fileinfo.attr.ctime = SystemTime::now();
+        Ok(())
+    }
 
-        return entries;
+    fn list_xattr(&self, ino: Ino) -> StoreResult<Vec<String>> {
+        if !self.files.contains_key(&ino) {
+            return Err(StoreError::NotFound);
+        }
+
+        Ok(self
+            .xattrs
+            .get(&ino)
+            .map(|xattrs| xattrs.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn remove_xattr(&mut self, ino: Ino, name: &str) -> StoreResult<()> {
+        let fileinfo = self.files.get_mut(&ino).ok_or(StoreError::NotFound)?;
+
+        self.xattrs
+            .get_mut(&ino)
+            .and_then(|xattrs| xattrs.remove(name))
+            .ok_or(StoreError::NoAttribute)?;
+        fileinfo.attr.ctime = SystemTime::now();
+        Ok(())
     }
 
     // Misc
-    fn get_file_attr(&self, ino: Ino) -> Option<FileAttr> {
+    fn get_file_attr(&self, ino: Ino) -> StoreResult<FileAttr> {
         let file = self.files.get(&ino);
         match file {
-            Some(fileinfo) => Some(fileinfo.attr),
-            None => None,
+            Some(fileinfo) => Ok(fileinfo.attr),
+            None => Err(StoreError::NotFound),
         }
     }
 
-    fn set_file_attr(
-        &mut self,
-        ino: Ino,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-    ) -> Option<FileAttr> {
-        let file = self.files.get_mut(&ino);
-        match file {
-            Some(fileinfo) => {
-                fileinfo.attr.size = size.unwrap_or(fileinfo.attr.size);
-                fileinfo.attr.uid = uid.unwrap_or(fileinfo.attr.uid);
-                fileinfo.attr.gid = gid.unwrap_or(fileinfo.attr.gid);
-                fileinfo.attr.atime = SystemTime::now();
-                fileinfo.attr.mtime = SystemTime::now();
-                fileinfo.attr.ctime = SystemTime::now();
-
-                Some(fileinfo.attr)
+    fn get_file_access(&self, ino: Ino) -> StoreResult<(FileAttr, Option<Vec<u8>>)> {
+        let attr = self.get_file_attr(ino)?;
+        let acl = self
+            .xattrs
+            .get(&ino)
+            .and_then(|xattrs| xattrs.get(ACL_ACCESS))
+            .cloned();
+
+        Ok((attr, acl))
+    }
+
+    fn set_file_attr(&mut self, ino: Ino, changes: AttrChanges) -> StoreResult<FileAttr> {
+        let fileinfo = self.files.get_mut(&ino).ok_or(StoreError::NotFound)?;
+        let owner = (fileinfo.attr.uid, fileinfo.attr.gid);
+
+        let mut len = 0;
+        if let Some(filedata) = self.files_data.get_mut(&ino) {
+            len = filedata.len() as u64;
+            if let Some(size) = changes.size {
+                let growth = size as i64 - len as i64;
+                self.quotas.check(owner.0, owner.1, growth, 0)?;
+
+                // Shrinking drops the tail, growing zero-fills up to the new size
+                filedata.resize(size as usize, 0);
+                self.quotas.charge(owner.0, owner.1, growth, 0);
+                len = size;
             }
-            None => None,
         }
+        changes.apply(&mut fileinfo.attr);
+        if let (Some(xattrs), Some(_)) = (self.xattrs.get_mut(&ino), changes.mode) {
+            chmod_acl(xattrs, fileinfo.attr.perm);
+        }
+
+        let new_owner = (fileinfo.attr.uid, fileinfo.attr.gid);
+        self.quotas.transfer(owner, new_owner, len);
+
+        Ok(fileinfo.attr)
+    }
+
+    fn stats(&self) -> StoreResult<StoreStats> {
+        let usage = self.quotas.usage();
+
+        Ok(StoreStats {
+            capacity: self.quotas.capacity(),
+            used_bytes: usage.bytes,
+            used_inodes: usage.inodes,
+        })
     }
 }
 
-fn create_attr(ino: Ino, uid: u32, gid: u32, kind: FileType) -> FileAttr {
-    let mut perm = 0o644;
-    if kind == FileType::Directory {
-        perm = 0o755;
+impl MemoryStore {
+    // A new entry needs an existing parent directory without anything named `name` in it
+    fn check_new_entry(&self, parent: Ino, name: &OsStr) -> StoreResult<()> {
+        check_name(name)?;
+
+        if !self.dirs.contains_key(&parent) {
+            return Err(StoreError::NotFound);
+        }
+
+        if self.find_child(parent, name).is_some() {
+            return Err(StoreError::AlreadyExists);
+        }
+
+        Ok(())
+    }
+
+    fn find_child(&self, parent: Ino, name: &OsStr) -> Option<Ino> {
+        self.dirs
+            .get(&parent)?
+            .iter()
+            .find(|(child_name, _)| child_name == name)
+            .map(|&(_, ino)| ino)
+    }
+
+    // Directories have a single parent, the root being its own
+    fn parent_dir(&self, ino: Ino) -> Ino {
+        self.files
+            .get(&ino)
+            .and_then(|info| info.parent)
+            .unwrap_or(ino)
+    }
+
+    fn has_children(&self, ino: Ino) -> bool {
+        self.dirs
+            .get(&ino)
+            .is_some_and(|children| !children.is_empty())
+    }
+
+    // Adds a directory entry, a subdirectory also counts as a link to its parent
+    fn attach(&mut self, parent: Ino, name: OsString, ino: Ino) {
+        self.dirs.entry(parent).or_default().push((name, ino));
+
+        if self.files[&ino].attr.kind == FileType::Directory {
+            if let Some(parent_info) = self.files.get_mut(&parent) {
+                parent_info.attr.nlink += 1;
+            }
+        }
+    }
+
+    fn detach(&mut self, parent: Ino, name: &OsStr) -> Option<Ino> {
+        let children = self.dirs.get_mut(&parent)?;
+        let index = children
+            .iter()
+            .position(|(child_name, _)| child_name == name)?;
+        let (_, ino) = children.remove(index);
+
+        if self.files[&ino].attr.kind == FileType::Directory {
+            if let Some(parent_info) = self.files.get_mut(&parent) {
+                parent_info.attr.nlink -= 1;
+            }
+        }
+
+        Some(ino)
+    }
+
+    // Drops one link to the inode after its directory entry was detached
+    fn drop_link(&mut self, ino: Ino) {
+        if let Some(fileinfo) = self.files.get_mut(&ino) {
+            if fileinfo.attr.kind == FileType::Directory {
+                fileinfo.attr.nlink = 0;
+            } else {
+                fileinfo.attr.nlink -= 1;
+            }
+            fileinfo.attr.ctime = SystemTime::now();
+        }
+
+        self.free_if_unused(ino);
     }
 
+    // Content is only freed once the last link is gone and nobody has the file open
+    fn free_if_unused(&mut self, ino: Ino) {
+        let unlinked = self
+            .files
+            .get(&ino)
+            .is_some_and(|info| info.attr.nlink == 0);
+
+        if unlinked && !self.open_handles.contains_key(&ino) {
+            if let Some(info) = self.files.remove(&ino) {
+                let len = self.files_data.get(&ino).map_or(0, |data| data.len());
+                self.quotas
+                    .charge(info.attr.uid, info.attr.gid, -(len as i64), -1);
+            }
+            self.files_data.remove(&ino);
+            self.xattrs.remove(&ino);
+            self.dirs.remove(&ino);
+        }
+    }
+
+    // Walks up the parents of `ino` looking for `ancestor`, `ino` counts as its own ancestor
+    fn is_ancestor(&self, ancestor: Ino, ino: Ino) -> bool {
+        let mut current = ino;
+        loop {
+            if current == ancestor {
+                return true;
+            }
+
+            match self.files.get(&current).and_then(|info| info.parent) {
+                Some(parent) if parent != current => current = parent,
+                _ => return false,
+            }
+        }
+    }
+}
+
+fn create_attr(ino: Ino, uid: u32, gid: u32, kind: FileType, perm: u16) -> FileAttr {
     FileAttr {
         ino,
         kind,
@@ -250,7 +710,7 @@ fn create_attr(ino: Ino, uid: u32, gid: u32, kind: FileType) -> FileAttr {
         mtime: SystemTime::now(),
         ctime: SystemTime::now(),
         crtime: SystemTime::now(),
-        nlink: 1,
+        nlink: if kind == FileType::Directory { 2 } else { 1 },
         rdev: 0,
         flags: 0,
         blksize: 512,
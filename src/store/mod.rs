@@ -1,3 +1,5 @@
+pub mod acl;
 pub mod etcd_store;
 pub mod memory_store;
+pub mod quota;
 pub mod store;
@@ -0,0 +1,116 @@
+use super::store::{Capacity, StoreError, StoreResult};
+use std::{collections::HashMap, env};
+
+#[derive(Default, Clone, Copy)]
+pub struct Usage {
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, bytes: i64, inodes: i64) {
+        self.bytes = self.bytes.saturating_add_signed(bytes);
+        self.inodes = self.inodes.saturating_add_signed(inodes);
+    }
+
+    // Only growth is checked, freeing space is always allowed
+    fn exceeds(&self, limit: &Capacity, bytes: i64, inodes: i64) -> bool {
+        (bytes > 0 && self.bytes.saturating_add_signed(bytes) > limit.bytes)
+            || (inodes > 0 && self.inodes.saturating_add_signed(inodes) > limit.inodes)
+    }
+}
+
+// Tracks usage against the store capacity and the optional per-user and per-group limits
+pub struct Quotas {
+    capacity: Capacity,
+    user_limits: HashMap<u32, Capacity>,
+    group_limits: HashMap<u32, Capacity>,
+    total: Usage,
+    users: HashMap<u32, Usage>,
+    groups: HashMap<u32, Usage>,
+}
+
+impl Quotas {
+    // Per-user and per-group limits are read from FUSEFS_USER_QUOTAS and FUSEFS_GROUP_QUOTAS,
+    // as comma separated `id:bytes:inodes` entries
+    pub fn from_env(default: Capacity) -> Quotas {
+        Quotas {
+            capacity: Capacity::from_env(default),
+            user_limits: get_limits_from_env("FUSEFS_USER_QUOTAS"),
+            group_limits: get_limits_from_env("FUSEFS_GROUP_QUOTAS"),
+            total: Usage::default(),
+            users: HashMap::new(),
+            groups: HashMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> Capacity {
+        self.capacity
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.total
+    }
+
+    // Fails if growing the usage of `uid` and `gid` by `bytes` and `inodes` goes over a limit
+    pub fn check(&self, uid: u32, gid: u32, bytes: i64, inodes: i64) -> StoreResult<()> {
+        if self.total.exceeds(&self.capacity, bytes, inodes) {
+            return Err(StoreError::NoSpace);
+        }
+
+        let user = self.users.get(&uid).copied().unwrap_or_default();
+        if let Some(limit) = self.user_limits.get(&uid) {
+            if user.exceeds(limit, bytes, inodes) {
+                return Err(StoreError::QuotaExceeded);
+            }
+        }
+
+        let group = self.groups.get(&gid).copied().unwrap_or_default();
+        if let Some(limit) = self.group_limits.get(&gid) {
+            if group.exceeds(limit, bytes, inodes) {
+                return Err(StoreError::QuotaExceeded);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn charge(&mut self, uid: u32, gid: u32, bytes: i64, inodes: i64) {
+        self.total.add(bytes, inodes);
+        self.users.entry(uid).or_default().add(bytes, inodes);
+        self.groups.entry(gid).or_default().add(bytes, inodes);
+    }
+
+    // Moves an inode holding `bytes` over to its new owner after a chown
+    pub fn transfer(&mut self, from: (u32, u32), to: (u32, u32), bytes: u64) {
+        if from != to {
+            self.charge(from.0, from.1, -(bytes as i64), -1);
+            self.charge(to.0, to.1, bytes as i64, 1);
+        }
+    }
+}
+
+fn get_limits_from_env(name: &str) -> HashMap<u32, Capacity> {
+    let mut limits = HashMap::new();
+    let Ok(value) = env::var(name) else {
+        return limits;
+    };
+
+    for entry in value.split(',').filter(|entry| !entry.is_empty()) {
+        let fields: Vec<_> = entry.split(':').map(|field| field.parse::<u64>()).collect();
+
+        match fields.as_slice() {
+            [Ok(id), Ok(bytes), Ok(inodes)] => {
+                let capacity = Capacity {
+                    bytes: *bytes,
+                    inodes: *inodes,
+                };
+                limits.insert(*id as u32, capacity);
+            }
+            _ => println!("Ignoring invalid quota [{}] in {}", entry, name),
+        }
+    }
+
+    println!("Proceeding with {} [{}]", name, value);
+    limits
+}
@@ -1,53 +1,294 @@
-use fuser::{FileAttr, FileType};
-use std::io;
+use super::acl::{set_acl, ACL_ACCESS, ACL_DEFAULT};
+use fuser::{FileAttr, FileType, TimeOrNow};
+use libc::{
+    EACCES, EBADF, EDQUOT, EEXIST, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENODATA, ENOENT, ENOSPC,
+    ENOTDIR, ENOTEMPTY, EPERM, XATTR_CREATE, XATTR_REPLACE,
+};
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::{OsStr, OsString},
+    fmt, io,
+    sync::mpsc,
+    time::SystemTime,
+};
 
 type Ino = u64;
 
 #[derive(Clone)]
 pub struct FileInfo {
     pub parent: Option<Ino>,
-    pub name: String,
+    pub name: OsString,
     pub attr: FileAttr,
 }
 
+// Attribute changes requested through setattr, fields left to None are kept as is
+#[derive(Default, Clone, Copy)]
+pub struct AttrChanges {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<TimeOrNow>,
+    pub mtime: Option<TimeOrNow>,
+    pub ctime: Option<SystemTime>,
+}
+
+impl AttrChanges {
+    // Updates the attributes only, resizing the file content is left to the store
+    pub fn apply(&self, attr: &mut FileAttr) {
+        let now = SystemTime::now();
+
+        if let Some(mode) = self.mode {
+            attr.perm = (mode & 0o7777) as u16;
+        }
+        attr.uid = self.uid.unwrap_or(attr.uid);
+        attr.gid = self.gid.unwrap_or(attr.gid);
+        if let Some(size) = self.size {
+            attr.size = size;
+            attr.mtime = now;
+        }
+        if let Some(atime) = self.atime {
+            attr.atime = resolve_time(atime, now);
+        }
+        if let Some(mtime) = self.mtime {
+            attr.mtime = resolve_time(mtime, now);
+        }
+        attr.ctime = self.ctime.unwrap_or(now);
+    }
+}
+
+fn resolve_time(time: TimeOrNow, now: SystemTime) -> SystemTime {
+    match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => now,
+    }
+}
+
+// Permission bits a new entry gets from the mode and umask passed on creation
+pub fn masked_perm(mode: u32, umask: u32) -> u16 {
+    (mode & !umask & 0o7777) as u16
+}
+
+// Errors the stores can fail with, each one is replied to the kernel as an errno
+#[derive(Debug)]
+pub enum StoreError {
+    NotFound,
+    AlreadyExists,
+    NotEmpty,
+    NotADirectory,
+    IsADirectory,
+    NotPermitted,
+    PermissionDenied,
+    BadFileHandle,
+    InvalidArgument,
+    NoAttribute,
+    NameTooLong,
+    NoSpace,
+    QuotaExceeded,
+    // The backend failed or handed back data that couldn't be decoded
+    Backend(String),
+}
+
+impl StoreError {
+    pub fn errno(&self) -> i32 {
+        match self {
+            StoreError::NotFound => ENOENT,
+            StoreError::AlreadyExists => EEXIST,
+            StoreError::NotEmpty => ENOTEMPTY,
+            StoreError::NotADirectory => ENOTDIR,
+            StoreError::IsADirectory => EISDIR,
+            StoreError::NotPermitted => EPERM,
+            StoreError::PermissionDenied => EACCES,
+            StoreError::BadFileHandle => EBADF,
+            StoreError::InvalidArgument => EINVAL,
+            StoreError::NoAttribute => ENODATA,
+            StoreError::NameTooLong => ENAMETOOLONG,
+            StoreError::NoSpace => ENOSPC,
+            StoreError::QuotaExceeded => EDQUOT,
+            StoreError::Backend(_) => EIO,
+        }
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Backend(msg) => write!(f, "store backend error: {}", msg),
+            e => write!(f, "{}", io::Error::from_raw_os_error(e.errno())),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+// Longest name a directory entry can have
+pub const NAME_MAX: usize = 255;
+
+pub fn check_name(name: &OsStr) -> StoreResult<()> {
+    if name.len() > NAME_MAX {
+        return Err(StoreError::NameTooLong);
+    }
+
+    Ok(())
+}
+
+// Space and inodes a store can hold, as reported through statfs
+#[derive(Clone, Copy)]
+pub struct Capacity {
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+impl Capacity {
+    // Overrides the store's defaults with FUSEFS_CAPACITY_BYTES and FUSEFS_CAPACITY_INODES
+    pub fn from_env(default: Capacity) -> Capacity {
+        Capacity {
+            bytes: get_u64_from_env("FUSEFS_CAPACITY_BYTES", default.bytes),
+            inodes: get_u64_from_env("FUSEFS_CAPACITY_INODES", default.inodes),
+        }
+    }
+}
+
+fn get_u64_from_env(name: &str, default: u64) -> u64 {
+    match env::var(name).ok().and_then(|value| value.parse().ok()) {
+        Some(value) => {
+            println!("Proceeding with {} [{}]", name, value);
+            value
+        }
+        None => default,
+    }
+}
+
+pub struct StoreStats {
+    pub capacity: Capacity,
+    pub used_bytes: u64,
+    pub used_inodes: u64,
+}
+
+// Extended attributes of a single inode, keyed by their full name (e.g. "user.tag")
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+// Sets an extended attribute honouring the XATTR_CREATE and XATTR_REPLACE flags,
+// ACLs are validated and may update the permission bits in `attr`
+pub fn set_xattr(
+    xattrs: &mut Xattrs,
+    attr: &mut FileAttr,
+    name: String,
+    value: &[u8],
+    flags: i32,
+) -> StoreResult<()> {
+    let exists = xattrs.contains_key(&name);
+
+    if flags & XATTR_CREATE != 0 && exists {
+        return Err(StoreError::AlreadyExists);
+    }
+    if flags & XATTR_REPLACE != 0 && !exists {
+        return Err(StoreError::NoAttribute);
+    }
+
+    if name == ACL_ACCESS || name == ACL_DEFAULT {
+        return set_acl(xattrs, attr, name, value);
+    }
+
+    xattrs.insert(name, value.to_vec());
+    Ok(())
+}
+
+// Kernel caches made stale by changes from other mounts of the same store
+#[derive(Debug)]
+pub enum Invalidation {
+    // Attributes and content of an inode
+    Inode(u64),
+    // A name in a directory
+    Entry(u64, OsString),
+}
+
 // Simplified interface to provide storage for files and directories
 pub trait Store: Send {
     type Ino: 'static;
 
-    fn new() -> io::Result<Self>
+    // Stores shared between mounts report the changes made by the others on `invalidations`
+    fn new(invalidations: mpsc::Sender<Invalidation>) -> StoreResult<Self>
     where
         Self: Sized;
 
     // Files
-    fn delete_file(&mut self, name: String) -> io::Result<()>;
-    fn write_data(&mut self, ino: Ino, data: &[u8], offset: i64) -> io::Result<u32>;
-    fn open_file(&self, ino: Ino) -> Option<Ino>;
-    fn read_data(&self, ino: Ino, offset: i64, size: u32) -> io::Result<Vec<u8>>;
+    fn delete_file(&mut self, parent: Ino, name: OsString) -> StoreResult<()>;
+    // Appending writes land at the current end of the file, `offset` is ignored for them
+    fn write_data(&mut self, ino: Ino, data: &[u8], offset: i64, append: bool) -> StoreResult<u32>;
+    // O_TRUNC in `flags` truncates the file as part of opening it
+    fn open_file(&mut self, ino: Ino, flags: i32) -> StoreResult<Ino>;
+    fn release_file(&mut self, ino: Ino) -> StoreResult<()>;
+    // Persists data the store buffered for the file, called on flush, fsync and release
+    fn flush_file(&mut self, ino: Ino) -> StoreResult<()>;
+    fn link_file(&mut self, ino: Ino, new_parent: Ino, new_name: OsString)
+        -> StoreResult<FileAttr>;
+    fn read_data(&self, ino: Ino, offset: i64, size: u32) -> StoreResult<Vec<u8>>;
     fn create_file(
         &mut self,
-        name: String,
+        name: OsString,
         parent: Ino,
         uid: u32,
         gid: u32,
-    ) -> io::Result<FileAttr>;
+        mode: u32,
+        umask: u32,
+    ) -> StoreResult<FileAttr>;
 
-    // Dirs
-    fn lookup_file(&self, name: String, parent: Ino) -> Option<(Ino, FileInfo)>;
-    fn create_dir(&mut self, name: String, parent: Ino, uid: u32, gid: u32)
-        -> io::Result<FileAttr>;
+    fn create_symlink(
+        &mut self,
+        name: OsString,
+        parent: Ino,
+        target: &[u8],
+        uid: u32,
+        gid: u32,
+    ) -> StoreResult<FileAttr>;
+    fn read_link(&self, ino: Ino) -> StoreResult<Vec<u8>>;
 
-    fn delete_dir(&mut self, name: String) -> io::Result<()>;
-    fn get_dir_entries(&self, ino: Ino) -> Vec<(u64, FileType, String)>;
+    // Dirs
+    fn lookup_file(&self, name: OsString, parent: Ino) -> StoreResult<(Ino, FileInfo)>;
+    fn create_dir(
+        &mut self,
+        name: OsString,
+        parent: Ino,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+        umask: u32,
+    ) -> StoreResult<FileAttr>;
 
-    // Misc
-    fn get_file_attr(&self, ino: Ino) -> Option<FileAttr>;
-    fn set_file_attr(
+    fn delete_dir(&mut self, parent: Ino, name: OsString) -> StoreResult<()>;
+    fn rename_entry(
         &mut self,
+        parent: Ino,
+        name: OsString,
+        new_parent: Ino,
+        new_name: OsString,
+        flags: u32,
+    ) -> StoreResult<()>;
+    fn get_dir_entries(&self, ino: Ino) -> StoreResult<Vec<(u64, FileType, OsString)>>;
+    // Up to `count` entries from `offset` on, along with their attributes
+    fn get_dir_entries_plus(
+        &self,
         ino: Ino,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-    ) -> Option<FileAttr>;
+        offset: usize,
+        count: usize,
+    ) -> StoreResult<Vec<(OsString, FileAttr)>>;
+
+    // Xattrs
+    fn get_xattr(&self, ino: Ino, name: &str) -> StoreResult<Vec<u8>>;
+    fn set_xattr(&mut self, ino: Ino, name: String, value: &[u8], flags: i32) -> StoreResult<()>;
+    fn list_xattr(&self, ino: Ino) -> StoreResult<Vec<String>>;
+    fn remove_xattr(&mut self, ino: Ino, name: &str) -> StoreResult<()>;
+
+    // Misc
+    fn get_file_attr(&self, ino: Ino) -> StoreResult<FileAttr>;
+    // Attributes along with the access ACL, read together for the permission checks
+    fn get_file_access(&self, ino: Ino) -> StoreResult<(FileAttr, Option<Vec<u8>>)>;
+    fn set_file_attr(&mut self, ino: Ino, changes: AttrChanges) -> StoreResult<FileAttr>;
+    fn stats(&self) -> StoreResult<StoreStats>;
 }
 
 #[derive(Debug)]
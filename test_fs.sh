@@ -2,7 +2,14 @@
 
 fs_dir=/tmp/fusefs
 
-cargo run& 
+# FUSEFS_STORE_TYPE=etcd runs the tests against a fresh etcd container
+if [ "$FUSEFS_STORE_TYPE" == "etcd" ]; then
+    ./start_etcd_docker.sh
+    echo "Waiting for 5 seconds for etcd to start..."
+    sleep 5
+fi
+
+FUSEFS_USER_QUOTAS=12345:4096:10 cargo run& 
 echo "Waiting for 10 seconds for Fuse server to start..."
 sleep 10
 
@@ -42,6 +49,286 @@ else
     exit 1
 fi
 
+echo "Writing at offsets..."
+printf "aaaaaaaaaa" > offsets.txt
+printf "bbb" | dd of=offsets.txt bs=1 seek=3 conv=notrunc status=none
+if printf "aaabbbaaaa" | cmp -s - offsets.txt; then
+    echo "Overwrite in the middle successful"
+else
+    echo "Overwrite in the middle failed"
+    kill $pid
+    exit 1
+fi
+printf "cc" | dd of=offsets.txt bs=1 seek=12 conv=notrunc status=none
+if printf "aaabbbaaaa\0\0cc" | cmp -s - offsets.txt; then
+    echo "Write past EOF successful"
+else
+    echo "Write past EOF failed"
+    kill $pid
+    exit 1
+fi
+rm offsets.txt
+
+echo "Truncating files..."
+echo "a longer line of text" > truncate.txt
+echo "short" > truncate.txt
+if [ "$(cat truncate.txt)" == "short" ]; then
+    echo "O_TRUNC open successful"
+else
+    echo "O_TRUNC open failed"
+    kill $pid
+    exit 1
+fi
+truncate -s 2 truncate.txt
+truncate -s 4 truncate.txt
+if printf "sh\0\0" | cmp -s - truncate.txt; then
+    echo "Truncate successful"
+else
+    echo "Truncate failed"
+    kill $pid
+    exit 1
+fi
+rm truncate.txt
+
+echo "Changing modes and timestamps..."
+touch attrs.txt
+chmod 0751 attrs.txt
+touch -d "2001-02-03 04:05:06" attrs.txt
+if [ "$(stat -c %a attrs.txt)" == "751" ] && [ "$(date -r attrs.txt +%Y)" == "2001" ]; then
+    echo "Modes and timestamps changed successfully"
+else
+    echo "Modes and timestamps not changed"
+    kill $pid
+    exit 1
+fi
+rm attrs.txt
+
+echo "Creating files with modes..."
+(umask 077 && touch private.txt && mkdir private_dir)
+install -m 0755 /dev/null script.sh
+if [ "$(stat -c %a private.txt)" == "600" ] && [ "$(stat -c %a private_dir)" == "700" ] && [ "$(stat -c %a script.sh)" == "755" ]; then
+    echo "Modes applied successfully"
+else
+    echo "Modes not applied"
+    kill $pid
+    exit 1
+fi
+rm private.txt script.sh
+rmdir private_dir
+
+echo "Creating symlinks..."
+ln -s dir1/file1.txt link.txt
+if [ -L link.txt ] && [ "$(readlink link.txt)" == "dir1/file1.txt" ] && [ "$(head -n 1 link.txt)" == "file1" ]; then
+    echo "Symlinks created successfully"
+else
+    echo "Symlinks not created"
+    kill $pid
+    exit 1
+fi
+rm link.txt
+
+echo "Renaming files..."
+echo "renamed" > rename_src.txt
+mv rename_src.txt dir2/rename_dst.txt
+echo "replaced" > dir2/replace_src.txt
+mv dir2/replace_src.txt dir2/rename_dst.txt
+if [ ! -f rename_src.txt ] && [ ! -f dir2/replace_src.txt ] && [ "$(cat dir2/rename_dst.txt)" == "replaced" ]; then
+    echo "Files renamed successfully"
+else
+    echo "Files not renamed"
+    kill $pid
+    exit 1
+fi
+
+echo "Removing same-named files..."
+echo "same1" > dir1/same.txt
+echo "same2" > dir2/same.txt
+rm dir1/same.txt
+if [ ! -f dir1/same.txt ] && [ "$(cat dir2/same.txt)" == "same2" ]; then
+    echo "Files removed from the right directory"
+else
+    echo "Wrong file removed"
+    kill $pid
+    exit 1
+fi
+
+echo "Creating hard links..."
+echo "linked" > link_src.txt
+ln link_src.txt dir1/link_dst.txt
+rm link_src.txt
+if [ "$(stat -c %h dir1/link_dst.txt)" == "1" ] && [ "$(cat dir1/link_dst.txt)" == "linked" ]; then
+    echo "Hard links created successfully"
+else
+    echo "Hard links not created"
+    kill $pid
+    exit 1
+fi
+
+echo "Counting directory links..."
+mkdir -p dir2/sub1 dir2/sub2
+if [ "$(stat -c %h dir2)" == "4" ] && [ "$(stat -c %h dir2/sub1)" == "2" ]; then
+    echo "Directory links counted successfully"
+else
+    echo "Directory links not counted"
+    kill $pid
+    exit 1
+fi
+rmdir dir2/sub1 dir2/sub2
+
+echo "Setting extended attributes..."
+echo "xattr" > xattr.txt
+setfattr -n user.tag -v hello xattr.txt
+if [ "$(getfattr --only-values -n user.tag xattr.txt)" == "hello" ] && setfattr -x user.tag xattr.txt && ! getfattr -n user.tag xattr.txt 2>/dev/null; then
+    echo "Extended attributes set successfully"
+else
+    echo "Extended attributes not set"
+    kill $pid
+    exit 1
+fi
+
+echo "Creating duplicate entries..."
+mkdir dup_dir
+echo "dup" > dup.txt
+if ! mkdir dup_dir 2>/dev/null && ! (set -o noclobber; echo "again" > dup.txt) 2>/dev/null && [ "$(ls | grep -c '^dup')" == "2" ]; then
+    echo "Duplicate entries rejected successfully"
+else
+    echo "Duplicate entries not rejected"
+    kill $pid
+    exit 1
+fi
+rmdir dup_dir
+rm dup.txt
+
+echo "Reporting errors..."
+long_name=$(printf 'a%.0s' {1..300})
+if touch "$long_name" 2>&1 | grep -q "File name too long" && rmdir dir1 2>&1 | grep -q "Directory not empty"; then
+    echo "Errors reported successfully"
+else
+    echo "Wrong errors reported"
+    kill $pid
+    exit 1
+fi
+
+echo "Creating files with non-UTF-8 names..."
+latin1_name=$(printf 'caf\xe9.txt')
+echo "latin1" > "$latin1_name"
+mv "$latin1_name" "dir1/$latin1_name"
+if [ "$(cat "dir1/$latin1_name")" == "latin1" ] && ls dir1 | grep -q "$latin1_name"; then
+    echo "Non-UTF-8 names handled successfully"
+else
+    echo "Non-UTF-8 names not handled"
+    kill $pid
+    exit 1
+fi
+
+echo "Reporting filesystem usage..."
+if [ "$(stat -f -c %b .)" -gt 0 ] && [ "$(stat -f -c %f .)" -lt "$(stat -f -c %b .)" ] && [ "$(stat -f -c %d .)" -lt "$(stat -f -c %c .)" ]; then
+    echo "Filesystem usage reported successfully"
+else
+    echo "Filesystem usage not reported"
+    kill $pid
+    exit 1
+fi
+
+echo "Enforcing quotas..."
+touch quota.txt
+chown 12345 quota.txt
+head -c 4096 /dev/zero >> quota.txt
+if head -c 1 /dev/zero 2>&1 >> quota.txt | grep -q "Disk quota exceeded" && [ "$(stat -c %s quota.txt)" == "4096" ]; then
+    echo "Quotas enforced successfully"
+else
+    echo "Quotas not enforced"
+    kill $pid
+    exit 1
+fi
+rm quota.txt
+
+echo "Checking permissions..."
+as_nobody="setpriv --reuid=65534 --regid=65534 --clear-groups"
+echo "Private" > private.txt
+chmod 600 private.txt
+mkdir shared
+chmod 1777 shared
+touch shared/owned.txt
+if ! $as_nobody cat private.txt 2>/dev/null && ! $as_nobody test -r private.txt && ! $as_nobody rm -f shared/owned.txt 2>/dev/null && $as_nobody touch shared/other.txt && [ -f shared/owned.txt ] && [ "$(stat -c %u shared/other.txt)" == "65534" ] && $as_nobody setfattr -n trusted.x -v 1 shared/other.txt 2>&1 | grep -q "Operation not permitted"; then
+    echo "Permissions checked successfully"
+else
+    echo "Permissions not checked"
+    kill $pid
+    exit 1
+fi
+rm -rf private.txt shared
+
+echo "Checking ACLs..."
+echo "Shared" > acl.txt
+chmod 600 acl.txt
+setfacl -m u:65534:r acl.txt
+mkdir acl_dir
+setfacl -d -m u:65534:rw acl_dir
+touch acl_dir/inherited.txt
+if $as_nobody cat acl.txt >/dev/null && getfacl -n acl_dir/inherited.txt | grep -q "^user:65534:rw-" && $as_nobody sh -c 'echo "Inherited" >> acl_dir/inherited.txt' && [ "$(stat -c %a acl_dir/inherited.txt)" == "664" ]; then
+    echo "ACLs checked successfully"
+else
+    echo "ACLs not checked"
+    kill $pid
+    exit 1
+fi
+rm -rf acl.txt acl_dir
+
+echo "Reading unlinked open files..."
+echo "Still open" > open.txt
+exec 3< open.txt
+rm open.txt
+if [ ! -f open.txt ] && [ "$(cat <&3)" == "Still open" ] && echo "Synced" | dd of=sync.txt conv=fsync 2>/dev/null && [ "$(cat sync.txt)" == "Synced" ]; then
+    echo "Unlinked open files read successfully"
+else
+    echo "Unlinked open files not read"
+    kill $pid
+    exit 1
+fi
+exec 3<&-
+rm sync.txt
+
+echo "Appending and truncating..."
+echo "First" > append.txt
+exec 3>> append.txt
+echo "Second" >> append.txt
+echo "Third" >&3
+exec 3>&-
+echo "Truncated" > trunc.txt
+echo "Short" > trunc.txt
+if [ "$(cat append.txt)" == "$(printf 'First\nSecond\nThird')" ] && [ "$(cat trunc.txt)" == "Short" ]; then
+    echo "Appended and truncated successfully"
+else
+    echo "Not appended and truncated"
+    kill $pid
+    exit 1
+fi
+rm append.txt trunc.txt
+
+echo "Listing dot entries..."
+mkdir -p dotdir/sub
+if [ "$(ls -ai dotdir/sub | awk '$2 == ".." {print $1}')" == "$(stat -c %i dotdir)" ] && [ "$(ls -ai dotdir/sub | awk '$2 == "." {print $1}')" == "$(stat -c %i dotdir/sub)" ] && [ "$(ls -ai . | awk '$2 == "." {print $1}')" == "$(stat -c %i .)" ]; then
+    echo "Dot entries listed successfully"
+else
+    echo "Dot entries not listed"
+    kill $pid
+    exit 1
+fi
+rm -r dotdir
+
+echo "Listing large directories..."
+mkdir large_dir
+for i in $(seq 300); do touch large_dir/file$i.txt; done
+if [ "$(ls -l large_dir | grep -c "file")" == "300" ] && [ "$(ls -a large_dir | sort | uniq -d | wc -l)" == "0" ]; then
+    echo "Large directory listed successfully"
+else
+    echo "Large directory not listed"
+    kill $pid
+    exit 1
+fi
+rm -r large_dir
+
 echo "Displaying files..."
 tree
 